@@ -0,0 +1,139 @@
+use crate::api::auth::Identity;
+use crate::api::model::AuthorizationError;
+use axum::http::Request;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// Where clients present their API key
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySource {
+    Header(String), // e.g. !header x-api-key
+    Query(String),  // e.g. !query api_key
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStatus {
+    #[default]
+    Active,
+    Revoked,
+}
+
+// An entry of the key store, stored under the SHA-256 of the key so that
+// the store never holds the keys themselves
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub id: String, // the identity of the key, e.g. acme-production
+    pub owner: String,
+    #[serde(default)]
+    pub status: KeyStatus,
+    pub plan: Option<String>,
+}
+
+pub struct ApiKeyStore {
+    pub source: ApiKeySource,
+    pub keys: HashMap<String, ApiKey>, // by hex encoded hash, lowercase
+}
+
+impl ApiKeyStore {
+    pub fn hash(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    // missing, unknown and revoked keys are all refused the same way
+    pub fn authenticate<T>(&self, req: &Request<T>) -> Result<Identity, AuthorizationError> {
+        let key = self
+            .presented(req)
+            .ok_or(AuthorizationError::Unauthenticated)?;
+        match self.keys.get(&ApiKeyStore::hash(&key)) {
+            Some(entry) if entry.status == KeyStatus::Active => Ok(Identity {
+                id: entry.id.clone(),
+                plan: entry.plan.clone(),
+            }),
+            _ => Err(AuthorizationError::Unauthenticated),
+        }
+    }
+
+    // the query without the key, so that it is not sent on to the upstream
+    pub fn strip_key(&self, query: &str) -> String {
+        let ApiKeySource::Query(name) = &self.source else {
+            return query.to_string();
+        };
+        query
+            .split('&')
+            .filter(|pair| pair.split('=').next() != Some(name.as_str()))
+            .collect::<Vec<&str>>()
+            .join("&")
+    }
+
+    fn presented<T>(&self, req: &Request<T>) -> Option<String> {
+        match &self.source {
+            ApiKeySource::Header(name) => {
+                let value = req.headers().get(name.as_str())?;
+                Some(String::from_utf8_lossy(value.as_bytes()).to_string())
+            }
+            ApiKeySource::Query(name) => req.uri().query()?.split('&').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                (key == name).then(|| value.to_string())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate_api_keys() {
+        let entry = |id: &str, status| ApiKey {
+            id: id.to_string(),
+            owner: "Acme".to_string(),
+            status,
+            plan: Some("pro".to_string()),
+        };
+        let store = ApiKeyStore {
+            source: ApiKeySource::Query("api_key".to_string()),
+            keys: HashMap::from([
+                (
+                    ApiKeyStore::hash("secret-1"),
+                    entry("acme", KeyStatus::Active),
+                ),
+                (
+                    ApiKeyStore::hash("secret-2"),
+                    entry("acme-old", KeyStatus::Revoked),
+                ),
+            ]),
+        };
+        let request = |uri: &str| Request::get(uri).body(()).unwrap();
+
+        let identity = store
+            .authenticate(&request("/pets?api_key=secret-1"))
+            .unwrap();
+        assert_eq!(identity.id, "acme");
+        assert_eq!(identity.plan.as_deref(), Some("pro"));
+        for uri in ["/pets?api_key=secret-2", "/pets?api_key=other", "/pets"] {
+            assert!(matches!(
+                store.authenticate(&request(uri)),
+                Err(AuthorizationError::Unauthenticated)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_query_key_not_forwarded() {
+        let store = ApiKeyStore {
+            source: ApiKeySource::Query("api_key".to_string()),
+            keys: HashMap::new(),
+        };
+        assert_eq!(
+            store.strip_key("q=rex&api_key=secret-1&page=2"),
+            "q=rex&page=2"
+        );
+        assert_eq!(store.strip_key("api_key=secret-1"), "");
+        assert_eq!(store.strip_key("api_key_hint=1"), "api_key_hint=1");
+    }
+}
@@ -0,0 +1,90 @@
+use crate::api::api_keys::ApiKeyStore;
+use crate::api::introspection::{Introspected, Introspector};
+use crate::api::jwt::JwtValidator;
+use crate::api::model::{AuthorizationError, QueryParams, UserQuery};
+use crate::api::signatures::HmacVerifier;
+use axum::http::Request;
+use serde::Deserialize;
+use std::sync::Arc;
+
+// Who a request was authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub id: String,           // counted by the rate limiter instead of the key extractor
+    pub plan: Option<String>, // selects the quota when the upstream uses plans
+}
+
+// How an upstream authenticates its requests, as written in the configuration
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    Hmac,
+    Introspection,
+}
+
+#[derive(Clone)]
+pub enum Authenticator {
+    ApiKey(Arc<ApiKeyStore>),
+    Jwt(Arc<JwtValidator>),
+    Hmac(Arc<HmacVerifier>),
+    Introspection(Arc<Introspector>),
+}
+
+impl Authenticator {
+    // the replay cache or the introspection answers of the authenticator it replaces
+    // are kept, with the new settings
+    pub fn carry_over(&mut self, previous: &[&Authenticator]) {
+        for previous in previous {
+            match (&mut *self, previous) {
+                (Authenticator::Hmac(verifier), Authenticator::Hmac(previous)) => {
+                    let mut carried = HmacVerifier::clone(verifier);
+                    carried.carry_over(previous);
+                    *verifier = Arc::new(carried);
+                    return;
+                }
+                (
+                    Authenticator::Introspection(introspector),
+                    Authenticator::Introspection(previous),
+                ) => {
+                    let mut carried = Introspector::clone(introspector);
+                    carried.carry_over(previous);
+                    *introspector = Arc::new(carried);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn authenticate<T>(
+        &self,
+        req: &Request<T>,
+        user_query: &UserQuery,
+    ) -> Result<Identity, AuthorizationError> {
+        match self {
+            Authenticator::ApiKey(store) => store.authenticate(req),
+            Authenticator::Jwt(validator) => {
+                let authorization = user_query.header.get(&QueryParams::Authorization);
+                validator.authenticate(authorization.map(|value| value.as_slice()))
+            }
+            Authenticator::Hmac(verifier) => verifier.authenticate(req),
+            // the endpoint was called before, requests that were not introspected are refused
+            Authenticator::Introspection(_) => req
+                .extensions()
+                .get::<Introspected>()
+                .map(|introspected| introspected.0.clone())
+                .ok_or(AuthorizationError::Unauthenticated),
+        }
+    }
+}
+
+// the token of an `Authorization: Bearer` header value
+pub fn bearer_token(authorization: Option<&[u8]>) -> Option<&str> {
+    let value = std::str::from_utf8(authorization?).ok()?;
+    let token = value
+        .strip_prefix("Bearer ")
+        .or(value.strip_prefix("bearer "))?;
+    Some(token.trim())
+}
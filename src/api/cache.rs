@@ -0,0 +1,459 @@
+use crate::api::auth::Identity;
+use axum::body::{Body, Bytes};
+use axum::http::header::{
+    AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, ETAG, IF_NONE_MATCH, VARY,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    pub max_body_size: usize, // larger responses are streamed and never stored
+    pub max_total_size: usize,
+    pub bypass_rate_limit: bool, // fresh hits do not consume quota
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 1000,
+            max_body_size: 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            bypass_rate_limit: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    stored: Instant,
+    expires: Instant,
+    vary: Vec<(HeaderName, Option<HeaderValue>)>, // request headers the response depends on
+    sequence: u64,
+}
+
+pub enum Lookup {
+    Fresh(CachedResponse),
+    Stale(HeaderValue), // the ETag to revalidate with
+    Miss,
+}
+
+#[derive(Default)]
+struct CacheState {
+    variants: HashMap<String, Vec<CachedResponse>>,
+    order: VecDeque<(String, u64)>, // oldest first, evicted first
+    size: usize,
+    count: usize,
+    sequence: u64,
+}
+
+// A shared in-memory cache of GET responses, honoring Cache-Control, ETag and Vary
+pub struct ResponseCache {
+    pub config: CacheConfig,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> ResponseCache {
+        ResponseCache {
+            config,
+            state: Arc::new(Mutex::new(CacheState::default())),
+        }
+    }
+
+    // the new bounds apply from the next store
+    pub fn carry_over(&mut self, previous: &ResponseCache) {
+        self.state = previous.state.clone();
+    }
+
+    // responses to authenticated requests are only served back to the same identity
+    pub fn key(upstream: &str, identity: Option<&Identity>, path_and_query: &str) -> String {
+        match identity {
+            Some(identity) => format!("{} {}\nidentity: {}", upstream, path_and_query, identity.id),
+            None => format!("{} {}", upstream, path_and_query),
+        }
+    }
+
+    pub fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Lookup {
+        let directives = cache_control(request_headers);
+        if directives.contains_key("no-store") {
+            return Lookup::Miss;
+        }
+        let state = self.state.lock().unwrap();
+        let Some(cached) = state
+            .variants
+            .get(key)
+            .and_then(|variants| variants.iter().find(|v| v.matches(request_headers)))
+        else {
+            return Lookup::Miss;
+        };
+        let etag = cached.headers.get(ETAG).cloned();
+        match (directives.contains_key("no-cache"), etag) {
+            (false, _) if cached.expires > Instant::now() => Lookup::Fresh(cached.clone()),
+            (_, Some(etag)) => Lookup::Stale(etag),
+            (_, None) => Lookup::Miss,
+        }
+    }
+
+    // how long the response can be stored for, None when it must not be
+    pub fn ttl(
+        &self,
+        request_headers: &HeaderMap,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Duration> {
+        if status != StatusCode::OK
+            || request_headers.contains_key(AUTHORIZATION)
+            || cache_control(request_headers).contains_key("no-store")
+        {
+            return None;
+        }
+        let too_large = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .is_none_or(|length| length > self.config.max_body_size);
+        if too_large || vary_names(headers).iter().any(|name| name == "*") {
+            return None;
+        }
+        let directives = cache_control(headers);
+        if ["no-store", "no-cache", "private"]
+            .iter()
+            .any(|directive| directives.contains_key(*directive))
+        {
+            return None;
+        }
+        freshness(headers)
+    }
+
+    pub fn store(
+        &self,
+        key: &str,
+        request_headers: &HeaderMap,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        ttl: Duration,
+    ) -> CachedResponse {
+        let now = Instant::now();
+        let vary = vary_names(&headers)
+            .into_iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .map(|name| {
+                let value = request_headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+        let mut cached = CachedResponse {
+            status,
+            headers,
+            body,
+            stored: now,
+            expires: now + ttl,
+            vary,
+            sequence: 0,
+        };
+        if cached.body.len() > self.config.max_body_size {
+            return cached;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.sequence += 1;
+        cached.sequence = state.sequence;
+        state.remove(key, |v| v.matches(request_headers));
+        state.size += cached.body.len();
+        state.count += 1;
+        state.order.push_back((key.to_string(), cached.sequence));
+        state
+            .variants
+            .entry(key.to_string())
+            .or_default()
+            .push(cached.clone());
+        while state.count > self.config.max_entries || state.size > self.config.max_total_size {
+            let Some((key, sequence)) = state.order.pop_front() else {
+                break;
+            };
+            state.remove(&key, |v| v.sequence == sequence);
+        }
+        // replaced responses leave their place in the order behind
+        if state.order.len() > 2 * state.count.max(1) {
+            state.compact();
+        }
+        cached
+    }
+
+    // the upstream answered 304 Not Modified, the stored response is fresh again
+    pub fn refresh(
+        &self,
+        key: &str,
+        request_headers: &HeaderMap,
+        ttl: Duration,
+    ) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let cached = state
+            .variants
+            .get_mut(key)?
+            .iter_mut()
+            .find(|v| v.matches(request_headers))?;
+        let now = Instant::now();
+        cached.stored = now;
+        cached.expires = now + ttl;
+        Some(cached.clone())
+    }
+}
+
+impl CacheState {
+    fn compact(&mut self) {
+        let order = std::mem::take(&mut self.order);
+        self.order = order
+            .into_iter()
+            .filter(|(key, sequence)| {
+                self.variants
+                    .get(key)
+                    .is_some_and(|variants| variants.iter().any(|v| v.sequence == *sequence))
+            })
+            .collect();
+    }
+
+    fn remove(&mut self, key: &str, predicate: impl Fn(&CachedResponse) -> bool) {
+        let Some(variants) = self.variants.get_mut(key) else {
+            return;
+        };
+        if let Some(index) = variants.iter().position(predicate) {
+            let removed = variants.remove(index);
+            self.size -= removed.body.len();
+            self.count -= 1;
+        }
+        if variants.is_empty() {
+            self.variants.remove(key);
+        }
+    }
+}
+
+impl CachedResponse {
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+
+    // 304 when the client already holds this version
+    pub fn into_response(self, request_headers: &HeaderMap) -> Response<Body> {
+        let etag = self.headers.get(ETAG);
+        let not_modified = etag.is_some_and(|etag| {
+            request_headers
+                .get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|tag| tag.trim() == "*" || tag.trim().as_bytes() == etag.as_bytes())
+        });
+
+        let mut response = Response::builder().status(self.status);
+        let headers = response
+            .headers_mut()
+            .expect("Oups! building response failed");
+        headers.extend(self.headers.clone());
+        headers.insert(AGE, HeaderValue::from(self.stored.elapsed().as_secs()));
+        if not_modified {
+            headers.remove(CONTENT_LENGTH);
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .expect("Oups! building response failed");
+        }
+        response
+            .body(Body::from(self.body))
+            .expect("Oups! building response failed")
+    }
+}
+
+// from s-maxage, or max-age, of a response
+// larger ages are taken as 2^31 seconds (RFC 9111 section 1.2.1)
+const MAX_AGE: u64 = 1 << 31;
+
+pub fn freshness(headers: &HeaderMap) -> Option<Duration> {
+    let directives = cache_control(headers);
+    let max_age = directives
+        .get("s-maxage")
+        .or(directives.get("max-age"))?
+        .as_deref()?;
+    let max_age = match max_age.parse::<u64>() {
+        Ok(max_age) => max_age.min(MAX_AGE),
+        Err(_) if !max_age.is_empty() && max_age.bytes().all(|b| b.is_ascii_digit()) => MAX_AGE,
+        Err(_) => return None,
+    };
+    (max_age > 0).then(|| Duration::from_secs(max_age))
+}
+
+// directive names are lowercased, values are unquoted
+fn cache_control(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn store(cache: &ResponseCache, key: &str, request: &HeaderMap, body: &'static str) {
+        let response = headers(&[
+            ("cache-control", "max-age=60"),
+            ("etag", "\"v1\""),
+            ("vary", "Accept-Language"),
+        ]);
+        cache.store(
+            key,
+            request,
+            StatusCode::OK,
+            response,
+            Bytes::from(body),
+            Duration::from_secs(60),
+        );
+    }
+
+    #[test]
+    fn test_ttl_from_cache_control() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        let ttl = |response: &[(&'static str, &'static str)]| {
+            let mut response = headers(response);
+            response.insert(CONTENT_LENGTH, HeaderValue::from(10));
+            cache.ttl(&HeaderMap::new(), StatusCode::OK, &response)
+        };
+
+        assert_eq!(
+            ttl(&[("cache-control", "public, max-age=60, s-maxage=30")]),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(ttl(&[("cache-control", "private, max-age=60")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=60"), ("vary", "*")]), None);
+        assert_eq!(ttl(&[]), None);
+    }
+
+    #[test]
+    fn test_huge_max_age_clamped() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        let clamped = Some(Duration::from_secs(MAX_AGE));
+        for max_age in [
+            "max-age=18446744073709551615",
+            "max-age=99999999999999999999999",
+        ] {
+            let mut response = headers(&[("cache-control", max_age)]);
+            assert_eq!(freshness(&response), clamped);
+            response.insert(CONTENT_LENGTH, HeaderValue::from(4));
+            let ttl = cache
+                .ttl(&HeaderMap::new(), StatusCode::OK, &response)
+                .unwrap();
+            cache.store(
+                "pets /pets",
+                &HeaderMap::new(),
+                StatusCode::OK,
+                response,
+                Bytes::from("pets"),
+                ttl,
+            );
+            assert!(
+                cache
+                    .refresh("pets /pets", &HeaderMap::new(), ttl)
+                    .is_some()
+            );
+        }
+        assert!(matches!(
+            cache.lookup("pets /pets", &HeaderMap::new()),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn test_vary_etag_and_eviction() {
+        let cache = ResponseCache::new(CacheConfig {
+            max_entries: 2,
+            ..CacheConfig::default()
+        });
+        let english = headers(&[("accept-language", "en")]);
+        let french = headers(&[("accept-language", "fr")]);
+        store(&cache, "pets /pets", &english, "pets");
+        store(&cache, "pets /pets", &french, "animaux");
+
+        let Lookup::Fresh(hit) = cache.lookup("pets /pets", &french) else {
+            panic!("expected a fresh hit");
+        };
+        assert_eq!(hit.body, "animaux");
+
+        // the client already has it
+        let conditional = headers(&[("accept-language", "fr"), ("if-none-match", "\"v1\"")]);
+        assert_eq!(
+            hit.into_response(&conditional).status(),
+            StatusCode::NOT_MODIFIED
+        );
+        let no_cache = headers(&[("accept-language", "fr"), ("cache-control", "no-cache")]);
+        assert!(matches!(
+            cache.lookup("pets /pets", &no_cache),
+            Lookup::Stale(etag) if etag == "\"v1\""
+        ));
+
+        // a third entry evicts the oldest one
+        store(&cache, "pets /pets/1", &english, "pet");
+        assert!(matches!(cache.lookup("pets /pets", &english), Lookup::Miss));
+        assert!(matches!(
+            cache.lookup("pets /pets", &french),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn test_replaced_responses_leave_the_order() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        let english = headers(&[("accept-language", "en")]);
+        for _ in 0..1000 {
+            store(&cache, "pets /pets", &english, "pets");
+        }
+        store(&cache, "pets /pets/1", &english, "pet");
+
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.count, 2);
+        assert!(state.order.len() <= 4);
+        assert_eq!(state.order.back().unwrap().0, "pets /pets/1");
+    }
+}
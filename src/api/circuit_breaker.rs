@@ -0,0 +1,280 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub window_size: usize,   // number of recent calls the rates are computed on
+    pub minimum_calls: usize, // no decision is taken below this number of calls
+    pub failure_rate_threshold: f64,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub slow_call_threshold: Duration,
+    pub slow_call_rate_threshold: f64,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub open_duration: Duration, // how long requests are rejected before probing
+    pub half_open_max_calls: usize, // probes allowed while half-open
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window_size: 20,
+            minimum_calls: 10,
+            failure_rate_threshold: 0.5,
+            slow_call_threshold: Duration::from_secs(5),
+            slow_call_rate_threshold: 0.8,
+            open_duration: Duration::from_secs(30),
+            half_open_max_calls: 3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: usize, successes: usize },
+}
+
+struct Outcome {
+    failed: bool,
+    slow: bool,
+}
+
+struct Inner {
+    state: CircuitState,
+    outcomes: VecDeque<Outcome>,
+}
+
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            inner: Arc::new(Mutex::new(Inner {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+            })),
+        }
+    }
+
+    // an open circuit stays open across a reload
+    pub fn carry_over(&mut self, previous: &CircuitBreaker) {
+        self.inner = previous.inner.clone();
+    }
+
+    // the permit of an allowed request records its result, or frees its probe when
+    // dropped without one, e.g. when the client went away mid-call
+    pub fn allow_request(&self) -> Option<CircuitPermit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::Open { until } => {
+                if Instant::now() < until {
+                    return None;
+                }
+                inner.state = CircuitState::HalfOpen {
+                    in_flight: 1,
+                    successes: 0,
+                };
+                true
+            }
+            CircuitState::HalfOpen {
+                ref mut in_flight,
+                successes,
+            } => {
+                if *in_flight + successes >= self.config.half_open_max_calls {
+                    return None;
+                }
+                *in_flight += 1;
+                true
+            }
+        };
+        Some(CircuitPermit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, success: bool, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let slow = latency >= self.config.slow_call_threshold;
+        match inner.state {
+            CircuitState::Closed => {
+                inner.outcomes.push_back(Outcome {
+                    failed: !success,
+                    slow,
+                });
+                if inner.outcomes.len() > self.config.window_size {
+                    inner.outcomes.pop_front();
+                }
+                if self.should_open(&inner.outcomes) {
+                    println!("Circuit opened");
+                    self.open(&mut inner);
+                }
+            }
+            // a late result from before the circuit opened
+            CircuitState::Open { .. } => {}
+            CircuitState::HalfOpen {
+                in_flight,
+                successes,
+            } => {
+                if !success || slow {
+                    println!("Circuit probe failed, opening again");
+                    self.open(&mut inner);
+                } else if successes + 1 >= self.config.half_open_max_calls {
+                    println!("Circuit closed");
+                    inner.state = CircuitState::Closed;
+                } else {
+                    inner.state = CircuitState::HalfOpen {
+                        in_flight: in_flight.saturating_sub(1),
+                        successes: successes + 1,
+                    };
+                }
+            }
+        }
+    }
+
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if let CircuitState::HalfOpen {
+            ref mut in_flight, ..
+        } = inner.state
+        {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_open(&self) -> bool {
+        matches!(self.inner.lock().unwrap().state, CircuitState::Open { .. })
+    }
+
+    fn should_open(&self, outcomes: &VecDeque<Outcome>) -> bool {
+        if outcomes.len() < self.config.minimum_calls {
+            return false;
+        }
+        let total = outcomes.len() as f64;
+        let failure_rate = outcomes.iter().filter(|o| o.failed).count() as f64 / total;
+        let slow_rate = outcomes.iter().filter(|o| o.slow).count() as f64 / total;
+        failure_rate >= self.config.failure_rate_threshold
+            || slow_rate >= self.config.slow_call_rate_threshold
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        inner.outcomes.clear();
+        inner.state = CircuitState::Open {
+            until: Instant::now() + self.config.open_duration,
+        };
+    }
+}
+
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool, // taken while half-open
+    recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn record(mut self, success: bool, latency: Duration) {
+        self.recorded = true;
+        self.breaker.record(success, latency);
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.release();
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(CircuitBreakerConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn circuit_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            window_size: 4,
+            minimum_calls: 4,
+            open_duration: Duration::from_millis(50),
+            half_open_max_calls: 2,
+            ..CircuitBreakerConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_circuit_opens_then_closes_after_probes() {
+        let breaker = circuit_breaker();
+        for success in [true, false, true, false] {
+            let permit = breaker.allow_request().unwrap();
+            permit.record(success, Duration::from_millis(1));
+        }
+        assert!(breaker.is_open());
+        assert!(breaker.allow_request().is_none());
+
+        thread::sleep(Duration::from_millis(60));
+        // half-open: only two probes go through
+        let first = breaker.allow_request().unwrap();
+        let second = breaker.allow_request().unwrap();
+        assert!(breaker.allow_request().is_none());
+        first.record(true, Duration::from_millis(1));
+        second.record(true, Duration::from_millis(1));
+        assert!(breaker.allow_request().is_some());
+    }
+
+    #[test]
+    fn test_slow_calls_and_failed_probe_open_circuit() {
+        let breaker = circuit_breaker();
+        for _ in 0..4 {
+            let permit = breaker.allow_request().unwrap();
+            permit.record(true, Duration::from_secs(6));
+        }
+        assert!(breaker.is_open());
+
+        thread::sleep(Duration::from_millis(60));
+        let probe = breaker.allow_request().unwrap();
+        probe.record(false, Duration::from_millis(1));
+        assert!(breaker.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_probe_frees_its_slot() {
+        let breaker = circuit_breaker();
+        for _ in 0..4 {
+            let permit = breaker.allow_request().unwrap();
+            permit.record(false, Duration::from_millis(1));
+        }
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // both probes are cancelled mid-call, as when the client disconnects
+        for _ in 0..2 {
+            let call = async {
+                let _permit = breaker.allow_request().unwrap();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            };
+            let cancelled = tokio::time::timeout(Duration::from_millis(10), call).await;
+            assert!(cancelled.is_err());
+        }
+        let probe = breaker.allow_request().unwrap();
+        probe.record(true, Duration::from_millis(1));
+        let probe = breaker.allow_request().unwrap();
+        probe.record(true, Duration::from_millis(1));
+        assert_eq!(breaker.inner.lock().unwrap().state, CircuitState::Closed);
+    }
+}
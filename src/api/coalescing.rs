@@ -0,0 +1,349 @@
+use crate::api::auth::Identity;
+use crate::api::cache::ResponseCache;
+use crate::api::model::{CallError, DownstreamError};
+use crate::api::streaming::BufferedResponse;
+use axum::body::{Body, Bytes};
+use axum::http::header::{ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, IF_NONE_MATCH};
+use axum::http::{HeaderMap, HeaderName, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoalescingConfig {
+    #[serde(deserialize_with = "crate::config::de::header_names")]
+    pub vary_headers: Vec<HeaderName>, // requests differing on these are not merged
+    pub max_body_size: usize, // the shared response is buffered
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        CoalescingConfig {
+            vary_headers: vec![ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION],
+            max_body_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+// CallError holds a response and cannot be cloned, this is what waiters get instead
+#[derive(Clone, Debug)]
+enum SharedError {
+    Unavailable,
+    CircuitOpen,
+    Timeout,
+    Failed(BufferedResponse), // the error response of the upstream call
+    TooLarge,                 // streamed to the caller that ran the call, the others run their own
+}
+
+type Shared = Arc<OnceCell<Result<BufferedResponse, SharedError>>>;
+
+// Single-flight: identical requests in flight share one upstream call
+pub struct RequestCoalescer {
+    pub config: CoalescingConfig,
+    in_flight: Mutex<HashMap<String, Shared>>,
+}
+
+impl RequestCoalescer {
+    pub fn new(config: CoalescingConfig) -> RequestCoalescer {
+        RequestCoalescer {
+            config,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // event streams never end, they cannot be buffered and shared
+    pub fn accepts(&self, request_headers: &HeaderMap) -> bool {
+        !request_headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .any(|accept| accept.contains("text/event-stream"))
+    }
+
+    // requests authenticated as different identities are never merged
+    pub fn key(
+        &self,
+        upstream: &str,
+        identity: Option<&Identity>,
+        path_and_query: &str,
+        request_headers: &HeaderMap,
+    ) -> String {
+        let mut key = ResponseCache::key(upstream, identity, path_and_query);
+        // a conditional request may get a 304 the others did not ask for
+        for name in self.config.vary_headers.iter().chain([&IF_NONE_MATCH]) {
+            for value in request_headers.get_all(name) {
+                key.push_str(&format!(
+                    "\n{}: {}",
+                    name,
+                    String::from_utf8_lossy(value.as_bytes())
+                ));
+            }
+        }
+        key
+    }
+
+    // the first caller runs the call, the others wait for its response,
+    // if that caller goes away one of the waiters takes over
+    pub async fn run(
+        &self,
+        key: String,
+        call: impl Future<Output = Result<Response<Body>, CallError>>,
+    ) -> Result<Response<Body>, CallError> {
+        let shared: Shared = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut call = Some(call);
+        let mut streamed = None;
+        let result = shared
+            .get_or_init(|| async {
+                let call = call.take().expect("the call runs once");
+                let (response, failed) = match call.await {
+                    Ok(response) => (response, false),
+                    Err(CallError::Downstream(DownstreamError::DownstreamError { response })) => {
+                        (response, true)
+                    }
+                    Err(err) => return Err(SharedError::from(err)),
+                };
+                match BufferedResponse::read(response, self.config.max_body_size).await {
+                    Ok(buffered) if failed => Err(SharedError::Failed(buffered)),
+                    Ok(buffered) => Ok(buffered),
+                    Err(Some(response)) => {
+                        streamed = Some((response, failed));
+                        Err(SharedError::TooLarge)
+                    }
+                    Err(None) => Err(SharedError::Failed(downstream_error("body not read"))),
+                }
+            })
+            .await
+            .clone();
+
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &shared))
+            {
+                in_flight.remove(&key);
+            }
+        }
+        match (result, streamed, call) {
+            (_, Some((response, false)), _) => Ok(response),
+            (_, Some((response, true)), _) => {
+                Err(CallError::Downstream(DownstreamError::DownstreamError {
+                    response,
+                }))
+            }
+            (Err(SharedError::TooLarge), None, Some(call)) => call.await,
+            (Ok(shared_response), _, _) => Ok(shared_response.into_response()),
+            (Err(err), _, _) => Err(err.into_call_error()),
+        }
+    }
+}
+
+fn downstream_error(err: impl fmt::Display) -> BufferedResponse {
+    BufferedResponse {
+        status: StatusCode::BAD_GATEWAY,
+        headers: HeaderMap::new(),
+        body: Bytes::from(format!("Downstream error: {}", err)),
+    }
+}
+
+impl From<CallError> for SharedError {
+    fn from(err: CallError) -> SharedError {
+        match err {
+            CallError::Downstream(DownstreamError::Unavailable) => SharedError::Unavailable,
+            CallError::Downstream(DownstreamError::CircuitOpen) => SharedError::CircuitOpen,
+            CallError::Downstream(DownstreamError::Timeout) => SharedError::Timeout,
+            // the other errors happen before the upstream is called
+            _ => SharedError::Failed(downstream_error("unexpected error")),
+        }
+    }
+}
+
+impl SharedError {
+    fn into_call_error(self) -> CallError {
+        match self {
+            SharedError::Unavailable => CallError::Downstream(DownstreamError::Unavailable),
+            SharedError::CircuitOpen => CallError::Downstream(DownstreamError::CircuitOpen),
+            SharedError::Timeout => CallError::Downstream(DownstreamError::Timeout),
+            // only read by the callers that cannot run the call again
+            SharedError::TooLarge => CallError::Downstream(DownstreamError::DownstreamError {
+                response: downstream_error("response too large to share").into_response(),
+            }),
+            SharedError::Failed(response) => {
+                CallError::Downstream(DownstreamError::DownstreamError {
+                    response: response.into_response(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api_keys::{ApiKey, ApiKeySource, ApiKeyStore, KeyStatus};
+    use crate::api::auth::Authenticator;
+    use crate::api::cache::CacheConfig;
+    use crate::api::proxy::Proxy;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use crate::engine::rate_limiter::RateLimiter;
+    use axum::Router;
+    use axum::http::Request;
+    use axum::routing::get;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_one_upstream_call() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let app = Router::new().route(
+            "/pets",
+            get(move || async move {
+                let hit = upstream_hits.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                format!("response {}", hit)
+            }),
+        );
+        let mut upstream = Upstream::new(
+            "pets",
+            &spawn_upstream(app).await,
+            RateLimiter::new(100, 60),
+        );
+        upstream.coalescing = Some(RequestCoalescer::new(CoalescingConfig::default()));
+        let http_proxy = proxy_to(upstream);
+        let get_pets = |language: &str| {
+            let req = Request::get("/pets")
+                .header("x-forwarded-for", "1.0.0.0")
+                .header("accept-language", language)
+                .body(Body::empty())
+                .unwrap();
+            let http_proxy = &http_proxy;
+            async move {
+                let res = http_proxy.proxy_handler(req).await.ok().unwrap();
+                axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let english = futures::future::join_all((0..5).map(|_| get_pets("en"))).await;
+        assert!(english.iter().all(|body| body == &english[0]));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // a different vary header value is a different request
+        let (english, french) = tokio::join!(get_pets("en"), get_pets("fr"));
+        assert_ne!(english, french);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_never_share_responses() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let app = Router::new().route(
+            "/orders",
+            get(move || async move {
+                let hit = upstream_hits.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                ([("cache-control", "max-age=60")], format!("orders {}", hit))
+            }),
+        );
+        let key = |id: &str| ApiKey {
+            id: id.to_string(),
+            owner: id.to_string(),
+            status: KeyStatus::Active,
+            plan: None,
+        };
+        let store = ApiKeyStore {
+            source: ApiKeySource::Header("x-api-key".to_string()),
+            keys: HashMap::from([
+                (ApiKeyStore::hash("key-acme"), key("acme")),
+                (ApiKeyStore::hash("key-globex"), key("globex")),
+            ]),
+        };
+        let mut upstream = Upstream::new(
+            "orders",
+            &spawn_upstream(app).await,
+            RateLimiter::new(100, 60),
+        );
+        upstream.authenticator = Some(Authenticator::ApiKey(Arc::new(store)));
+        upstream.coalescing = Some(RequestCoalescer::new(CoalescingConfig::default()));
+        upstream.cache = Some(ResponseCache::new(CacheConfig::default()));
+        let http_proxy = proxy_to(upstream);
+        let get_orders = |api_key: &str| {
+            let req = Request::get("/orders")
+                .header("x-forwarded-for", "1.0.0.0")
+                .header("x-api-key", api_key)
+                .body(Body::empty())
+                .unwrap();
+            let http_proxy = &http_proxy;
+            async move {
+                let res = http_proxy.proxy_handler(req).await.ok().unwrap();
+                axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // concurrent, then cached, each tenant only ever gets its own response
+        let (acme, globex) = tokio::join!(get_orders("key-acme"), get_orders("key-globex"));
+        assert_ne!(acme, globex);
+        assert_eq!(get_orders("key-acme").await, acme);
+        assert_eq!(get_orders("key-globex").await, globex);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_response_over_the_limit_is_not_shared() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let app = Router::new().route(
+            "/export",
+            get(move || async move {
+                upstream_hits.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                "x".repeat(3000)
+            }),
+        );
+        let mut upstream = Upstream::new(
+            "export",
+            &spawn_upstream(app).await,
+            RateLimiter::new(100, 60),
+        );
+        upstream.coalescing = Some(RequestCoalescer::new(CoalescingConfig {
+            max_body_size: 1024,
+            ..CoalescingConfig::default()
+        }));
+        let http_proxy = proxy_to(upstream);
+        let get_export = || {
+            let req = Request::get("/export")
+                .header("x-forwarded-for", "1.0.0.0")
+                .body(Body::empty())
+                .unwrap();
+            let http_proxy = &http_proxy;
+            async move {
+                let res = http_proxy.proxy_handler(req).await.ok().unwrap();
+                let status = res.status();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, body.len())
+            }
+        };
+
+        // the first caller gets it streamed, the others ask the upstream themselves
+        let responses = futures::future::join_all((0..3).map(|_| get_export())).await;
+        assert!(responses.iter().all(|res| *res == (StatusCode::OK, 3000)));
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,203 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const FORWARDED: &str = "forwarded";
+const VIA: &str = "via";
+
+// Controls which forwarding headers are added to the outbound request,
+// each one can be switched off independently
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingConfig {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    pub forwarded: bool,     // RFC 7239, off by default
+    pub via: Option<String>, // pseudonym used in the Via header, None disables it
+    #[serde(deserialize_with = "crate::config::de::cidrs")]
+    pub trusted_proxies: Vec<(IpAddr, u8)>, // whose X-Forwarded-For is believed, none by default
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        ForwardingConfig {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            forwarded: false,
+            via: Some("rate-limiter".to_string()),
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+// Set by the listener that accepted the connection, e.g. "https" for TLS
+#[derive(Clone, Copy, Debug)]
+pub struct ListenerProto(pub &'static str);
+
+// What the proxy knows about the original client connection
+#[derive(Debug, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub host: Option<String>,
+    pub proto: String,
+}
+
+impl ForwardingConfig {
+    pub fn apply(&self, headers: &mut HeaderMap, client: &ClientInfo) {
+        if self.x_forwarded_for
+            && let Some(ip) = client.ip
+        {
+            append_list(headers, X_FORWARDED_FOR, &ip.to_string());
+        }
+        if self.x_forwarded_proto {
+            set(headers, X_FORWARDED_PROTO, &client.proto);
+        }
+        if self.x_forwarded_host
+            && let Some(host) = &client.host
+        {
+            set(headers, X_FORWARDED_HOST, host);
+        }
+        if self.forwarded {
+            append_list(headers, FORWARDED, &forwarded_element(client));
+        }
+        if let Some(pseudonym) = &self.via {
+            append_list(headers, VIA, &format!("1.1 {}", pseudonym));
+        }
+    }
+
+    // The address of the client, for client_ip rules. X-Forwarded-For is only believed when
+    // the peer is a trusted proxy, it is read from the right, skipping the trusted proxies.
+    pub fn client_ip(&self, headers: &HeaderMap, client: &ClientInfo) -> Option<IpAddr> {
+        let trusted = |ip: IpAddr| {
+            self.trusted_proxies
+                .iter()
+                .any(|(network, prefix)| in_cidr(ip, *network, *prefix))
+        };
+        let mut ip = client.ip?;
+        let forwarded: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for address in forwarded.iter().rev() {
+            if !trusted(ip) {
+                break;
+            }
+            match address.trim().parse() {
+                Ok(forwarded_ip) => ip = forwarded_ip,
+                Err(_) => break,
+            }
+        }
+        Some(ip)
+    }
+}
+
+fn forwarded_element(client: &ClientInfo) -> String {
+    let mut pairs: Vec<String> = vec![];
+    match client.ip {
+        // IPv6 addresses must be quoted and bracketed (RFC 7239 section 6)
+        Some(IpAddr::V6(ip)) => pairs.push(format!("for=\"[{}]\"", ip)),
+        Some(IpAddr::V4(ip)) => pairs.push(format!("for={}", ip)),
+        None => pairs.push("for=unknown".to_string()),
+    }
+    if let Some(host) = &client.host {
+        pairs.push(format!("host=\"{}\"", host));
+    }
+    pairs.push(format!("proto={}", client.proto));
+    pairs.join(";")
+}
+
+fn append_list(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let header_name = HeaderName::from_static(name);
+    let joined = match headers.get(&header_name).and_then(|v| v.to_str().ok()) {
+        Some(existing) if !existing.is_empty() => format!("{}, {}", existing, value),
+        _ => value.to_string(),
+    };
+    if let Ok(header_value) = HeaderValue::from_str(&joined) {
+        headers.insert(header_name, header_value);
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(header_value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), header_value);
+    }
+}
+
+pub fn in_cidr(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (network, prefix) = match cidr.split_once('/') {
+        Some((network, prefix)) => (network.parse::<IpAddr>().ok()?, prefix.parse::<u8>().ok()?),
+        None => {
+            let network = cidr.parse::<IpAddr>().ok()?;
+            (network, if network.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let max = if network.is_ipv4() { 32 } else { 128 };
+    (prefix <= max).then_some((network, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_forwarding_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("1.0.0.0"));
+        let client = ClientInfo {
+            ip: Some("10.0.0.1".parse().unwrap()),
+            host: Some("api.example.com".to_string()),
+            proto: "http".to_string(),
+        };
+        let config = ForwardingConfig {
+            forwarded: true,
+            ..ForwardingConfig::default()
+        };
+
+        config.apply(&mut headers, &client);
+
+        assert_eq!(headers[X_FORWARDED_FOR], "1.0.0.0, 10.0.0.1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "api.example.com");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=10.0.0.1;host=\"api.example.com\";proto=http"
+        );
+        assert_eq!(headers[VIA], "1.1 rate-limiter");
+    }
+
+    #[test]
+    fn test_disabled_forwarding_headers() {
+        let mut headers = HeaderMap::new();
+        let config = ForwardingConfig {
+            x_forwarded_for: false,
+            x_forwarded_proto: false,
+            x_forwarded_host: false,
+            forwarded: false,
+            via: None,
+            trusted_proxies: vec![],
+        };
+        config.apply(&mut headers, &ClientInfo::default());
+        assert!(headers.is_empty());
+    }
+}
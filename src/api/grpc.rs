@@ -0,0 +1,214 @@
+use crate::api::model::{AuthorizationError, CallError, DownstreamError, RoutingError};
+use crate::api::upstream_pool::BackendGuard;
+use crate::engine::rate_limiter::RateLimiter;
+use axum::body::{Body, Bytes};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderValue, Request, Response};
+use http_body::{Frame, SizeHint};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+pub const INVALID_ARGUMENT: u8 = 3;
+pub const DEADLINE_EXCEEDED: u8 = 4;
+pub const RESOURCE_EXHAUSTED: u8 = 8;
+pub const UNIMPLEMENTED: u8 = 12;
+pub const INTERNAL: u8 = 13;
+pub const UNAVAILABLE: u8 = 14;
+pub const UNAUTHENTICATED: u8 = 16;
+
+#[derive(Default)]
+pub struct GrpcConfig {
+    // by fully-qualified method name, e.g. pets.PetService/GetPet
+    // methods without their own limiter share the upstream one
+    pub method_limiters: HashMap<String, RateLimiter>,
+}
+
+pub fn is_grpc_request<T>(req: &Request<T>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+}
+
+// "/package.Service/Method" gives "package.Service/Method"
+pub fn method_name<T>(req: &Request<T>) -> Option<String> {
+    let (service, method) = req.uri().path().strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some(format!("{}/{}", service, method))
+}
+
+// a trailers-only response: the status travels in the headers of an empty body
+pub fn status_response(code: u8, message: &str) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", code.to_string())
+        .header(
+            "grpc-message",
+            HeaderValue::from_str(message).unwrap_or(HeaderValue::from_static("")),
+        )
+        .body(Body::empty())
+        .expect("Oups! building response failed")
+}
+
+pub fn error_response(err: CallError) -> Response<Body> {
+    match err {
+        CallError::Authorization(AuthorizationError::TooManyQueries) => {
+            status_response(RESOURCE_EXHAUSTED, "Too Many Requests")
+        }
+        CallError::Authorization(AuthorizationError::IpHeaderMissing) => {
+            status_response(INVALID_ARGUMENT, "IP Header Missing")
+        }
+        CallError::Authorization(AuthorizationError::Unauthenticated) => {
+            status_response(UNAUTHENTICATED, "Unauthorized")
+        }
+        CallError::Routing(RoutingError::NoRoute) => {
+            status_response(UNIMPLEMENTED, "No Route Found")
+        }
+        CallError::Downstream(DownstreamError::Timeout) => {
+            status_response(DEADLINE_EXCEEDED, "Gateway Timeout")
+        }
+        CallError::Downstream(_) => status_response(UNAVAILABLE, "Service Unavailable"),
+        _ => status_response(INTERNAL, "Internal Proxy Server Error"),
+    }
+}
+
+// Relays the upstream response frame by frame, trailers included
+pub fn into_response(res: reqwest::Response, backend: BackendGuard) -> Response<Body> {
+    let (parts, body) = axum::http::Response::<reqwest::Body>::from(res).into_parts();
+    let body = GuardedBody {
+        inner: body,
+        _backend: backend,
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+struct GuardedBody {
+    inner: reqwest::Body,
+    _backend: BackendGuard, // the backend slot stays taken while the call is streamed
+}
+
+impl http_body::Body for GuardedBody {
+    type Data = Bytes;
+    type Error = reqwest::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, reqwest::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::http_proxy::HttpProxy;
+    use crate::api::protocol::UpstreamProtocol;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use axum::Router;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use std::collections::VecDeque;
+
+    // a unary gRPC answer: one message, then the status in the trailers
+    struct UnaryBody(VecDeque<Frame<Bytes>>);
+
+    impl http_body::Body for UnaryBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    async fn get_pet() -> Response<Body> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = VecDeque::from([
+            Frame::data(Bytes::from_static(b"\0\0\0\0\x03pet")),
+            Frame::trailers(trailers),
+        ]);
+        Response::builder()
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(Body::new(UnaryBody(frames)))
+            .unwrap()
+    }
+
+    async fn proxy() -> HttpProxy {
+        let app = Router::new().route("/pets.PetService/GetPet", post(get_pet));
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(10, 60))
+                .with_protocol(UpstreamProtocol::Http2)
+                .unwrap();
+        upstream.grpc.method_limiters.insert(
+            "pets.PetService/GetPet".to_string(),
+            RateLimiter::new(1, 60),
+        );
+        proxy_to(upstream)
+    }
+
+    fn call() -> Request<Body> {
+        Request::post("/pets.PetService/GetPet")
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("x-forwarded-for", "1.0.0.0")
+            .body(Body::from(Bytes::from_static(b"\0\0\0\0\x01\x08")))
+            .unwrap()
+    }
+
+    async fn frames(res: Response<Body>) -> Vec<Frame<Bytes>> {
+        let mut body = res.into_body();
+        let mut frames = vec![];
+        while let Some(frame) =
+            std::future::poll_fn(|cx| http_body::Body::poll_frame(Pin::new(&mut body), cx)).await
+        {
+            frames.push(frame.unwrap());
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_grpc_trailers_and_method_limit() {
+        let proxy = proxy().await;
+
+        let res = proxy.grpc_handler(call()).await.ok().unwrap();
+        let frames = frames(res).await;
+        assert_eq!(frames[0].data_ref().unwrap(), "\0\0\0\0\x03pet");
+        assert_eq!(frames[1].trailers_ref().unwrap()["grpc-status"], "0");
+
+        // the second call is over the limit of the method
+        let res = match proxy.grpc_handler(call()).await {
+            Ok(_) => panic!("the method limit was not applied"),
+            Err(err) => error_response(err),
+        };
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["grpc-status"], "8");
+    }
+
+    #[test]
+    fn test_method_name() {
+        let req = |path: &str| Request::post(path).body(()).unwrap();
+        assert_eq!(
+            method_name(&req("/pets.PetService/GetPet")).as_deref(),
+            Some("pets.PetService/GetPet")
+        );
+        assert_eq!(method_name(&req("/pets")), None);
+        assert_eq!(method_name(&req("/pets/1/owner")), None);
+    }
+}
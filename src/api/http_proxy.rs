@@ -1,43 +1,427 @@
+use crate::api::auth::{Authenticator, Identity, bearer_token};
+use crate::api::cache::{self, Lookup, ResponseCache};
+use crate::api::forwarding::{ClientInfo, ForwardingConfig, ListenerProto};
+use crate::api::grpc;
+use crate::api::idempotency::{Begin, IdempotencyStore};
+use crate::api::introspection::{Introspected, IntrospectionError};
 use crate::api::model::{
-    AuthorizationError, CallError, DownstreamError, QueryIp, QueryParams, UserQuery, Verb,
+    AuthorizationError, CallError, DownstreamError, IdempotencyError, QueryIp, QueryParams,
+    RoutingError, UpstreamError, UserQuery, Verb,
 };
 use crate::api::proxy::Proxy;
+use crate::api::retry::RetryPolicy;
+use crate::api::routing::{ResolvedRoute, RoutingTable, Upstream};
+use crate::api::rules::RequestFacts;
+use crate::api::signatures::BodyDigest;
+use crate::api::streaming;
+use crate::api::upstream_pool::BackendGuard;
+use crate::api::websocket;
 use crate::engine::rate_limiter::RateLimiter;
 use axum::body::Body;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::header::{HOST, IF_NONE_MATCH, TE};
 use axum::http::request::Parts;
-use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, Uri};
+use axum::http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+};
+use axum::response::IntoResponse;
 use reqwest::Client;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Clone)]
 pub struct HttpProxy {
-    pub rate_limiter: RateLimiter,
+    pub routing: Arc<RoutingTable>,
     pub client: reqwest::Client,
-    pub original_url: String,
+    pub forwarding: ForwardingConfig,
 }
 
 impl<T> Proxy<Request<T>> for HttpProxy {
-    async fn proxy_handler(&self, req: Request<T>) -> Result<(), CallError> {
+    async fn proxy_handler(&self, req: Request<T>) -> Result<Response<Body>, CallError> {
         let user_query: UserQuery = self.map(&req);
         println!("User query: {:?}", user_query);
 
-        if self.check_user_authorization(&user_query).is_err() {
+        let route: ResolvedRoute = self
+            .resolve_route(&req, &user_query)
+            .map_err(CallError::Routing)?;
+        // even cached responses are only served to authenticated clients
+        let identity = self
+            .authenticate(&req, &user_query, &route.upstream)
+            .map_err(CallError::Authorization)?;
+
+        // GET responses the upstream allows to be stored
+        let cache = route
+            .upstream
+            .cache
+            .as_ref()
+            .filter(|_| matches!(user_query.verb, Verb::GET));
+        let cache_key = ResponseCache::key(
+            &route.upstream.name,
+            identity.as_ref(),
+            &route.forwarded_path,
+        );
+        let lookup = cache.map(|cache| cache.lookup(&cache_key, req.headers()));
+        let facts = RequestFacts::new(&req, &user_query.client, &self.forwarding);
+        let (limiter, key_extractor) = route.upstream.limit_for(&facts);
+        let rate_limit_key = identity
+            .as_ref()
+            .map(|identity| identity.id.clone())
+            .or_else(|| key_extractor.extract(req.headers(), &user_query.client));
+        let plan = identity
+            .as_ref()
+            .and_then(|identity| identity.plan.as_deref());
+        let revalidate: Option<HeaderValue> = match lookup {
+            Some(Lookup::Fresh(cached)) => {
+                if !cache.is_some_and(|c| c.config.bypass_rate_limit) {
+                    self.authorize(rate_limit_key.as_deref(), plan, limiter)
+                        .map_err(CallError::Authorization)?;
+                }
+                return Ok(cached.into_response(req.headers()));
+            }
+            Some(Lookup::Stale(etag)) => Some(etag),
+            Some(Lookup::Miss) | None => None,
+        };
+        self.authorize(rate_limit_key.as_deref(), plan, limiter)
+            .map_err(CallError::Authorization)?;
+
+        // a retried POST with the same Idempotency-Key gets the first response
+        let idempotency_key = user_query.header.get(&QueryParams::IdempotencyKey);
+        if let Some(store) = route.upstream.idempotency.as_ref()
+            && let Some(idempotency_key) = idempotency_key
+            && !matches!(user_query.verb, Verb::GET)
+        {
+            let key = IdempotencyStore::key(
+                identity.as_ref(),
+                self.forwarding.client_ip(req.headers(), &user_query.client),
+                user_query.verb.as_ref(),
+                &route.forwarded_path,
+                idempotency_key,
+            );
+            return match store.begin(&key) {
+                Begin::Proceed(guard) => {
+                    let forward = self.forward(
+                        &route,
+                        &user_query,
+                        identity.as_ref(),
+                        revalidate,
+                        req.headers(),
+                    );
+                    match forward.await {
+                        Ok(response) => Ok(guard.complete(response).await),
+                        Err(
+                            err @ CallError::Downstream(
+                                DownstreamError::Unavailable | DownstreamError::CircuitOpen,
+                            ),
+                        ) => {
+                            guard.release();
+                            Err(err)
+                        }
+                        // the guard marks the key as sent
+                        Err(err) => Err(err),
+                    }
+                }
+                Begin::Replay(response) => Ok(response),
+                Begin::Conflict => Err(CallError::Idempotency(IdempotencyError::Conflict)),
+                Begin::Sent => Err(CallError::Idempotency(IdempotencyError::Sent)),
+            };
+        }
+
+        // identical requests in flight share a single upstream call,
+        // only GETs since request bodies are not compared
+        let coalescer = route
+            .upstream
+            .coalescing
+            .as_ref()
+            .filter(|c| matches!(user_query.verb, Verb::GET) && c.accepts(req.headers()));
+        let forward = self.forward(
+            &route,
+            &user_query,
+            identity.as_ref(),
+            revalidate,
+            req.headers(),
+        );
+        match coalescer {
+            Some(coalescer) => {
+                let key = coalescer.key(
+                    &route.upstream.name,
+                    identity.as_ref(),
+                    &route.forwarded_path,
+                    req.headers(),
+                );
+                coalescer.run(key, forward).await
+            }
+            None => forward.await,
+        }
+    }
+}
+
+impl HttpProxy {
+    // signatures cover the body, so requests to upstreams checking them have their body
+    // read and hashed before anything else, within the configured size, and opaque tokens
+    // are introspected here since the authenticators cannot wait on the endpoint
+    pub async fn prepare_authentication(
+        &self,
+        mut req: Request<Body>,
+    ) -> Result<Request<Body>, CallError> {
+        let user_query: UserQuery = self.map(&req);
+        let Ok(route) = self.resolve_route(&req, &user_query) else {
+            return Ok(req);
+        };
+        match &route.upstream.authenticator {
+            Some(Authenticator::Hmac(verifier)) => {
+                let (mut parts, body) = req.into_parts();
+                let body = axum::body::to_bytes(body, verifier.max_body_size)
+                    .await
+                    .map_err(|err| {
+                        println!("Signed body not read: {}", err);
+                        CallError::Authorization(AuthorizationError::Unauthenticated)
+                    })?;
+                parts.extensions.insert(BodyDigest::of(&body));
+                Ok(Request::from_parts(parts, Body::from(body)))
+            }
+            Some(Authenticator::Introspection(introspector)) => {
+                let authorization = user_query.header.get(&QueryParams::Authorization);
+                let token = bearer_token(authorization.map(|value| value.as_slice())).ok_or(
+                    CallError::Authorization(AuthorizationError::Unauthenticated),
+                )?;
+                let client_ip = self.forwarding.client_ip(req.headers(), &user_query.client);
+                let identity = match introspector.introspect(token, client_ip).await {
+                    Ok(identity) => identity,
+                    Err(IntrospectionError::TooManyLookups) => {
+                        return Err(CallError::Authorization(AuthorizationError::TooManyQueries));
+                    }
+                    Err(err) => {
+                        println!("Introspection error: {}", err);
+                        return Err(CallError::Downstream(DownstreamError::Unavailable));
+                    }
+                };
+                let Some(identity) = identity else {
+                    println!("Inactive token");
+                    return Err(CallError::Authorization(
+                        AuthorizationError::Unauthenticated,
+                    ));
+                };
+                req.extensions_mut().insert(Introspected(identity));
+                Ok(req)
+            }
+            _ => Ok(req),
+        }
+    }
+
+    // rate limited at handshake time, then tunneled until either side closes
+    pub async fn websocket_handler(&self, req: Request<Body>) -> Result<Response<Body>, CallError> {
+        let user_query: UserQuery = self.map(&req);
+        let route: ResolvedRoute = self
+            .resolve_route(&req, &user_query)
+            .map_err(CallError::Routing)?;
+
+        let identity = self
+            .authenticate(&req, &user_query, &route.upstream)
+            .map_err(CallError::Authorization)?;
+        let facts = RequestFacts::new(&req, &user_query.client, &self.forwarding);
+        let (limiter, key_extractor) = route.upstream.limit_for(&facts);
+        let rate_limit_key = identity
+            .as_ref()
+            .map(|identity| identity.id.clone())
+            .or_else(|| key_extractor.extract(req.headers(), &user_query.client));
+        let plan = identity
+            .as_ref()
+            .and_then(|identity| identity.plan.as_deref());
+        self.authorize(rate_limit_key.as_deref(), plan, limiter)
+            .map_err(CallError::Authorization)?;
+        // messages are counted by the key of the handshake, requests without one were refused
+        let rate_limit_key = rate_limit_key.unwrap_or_default();
+
+        let (mut parts, _body) = req.into_parts();
+        let upgrade = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+            Ok(upgrade) => upgrade,
+            Err(rejection) => return Ok(rejection.into_response()),
+        };
+
+        let client_key = self.client_key(&user_query);
+        let backend: BackendGuard = route
+            .upstream
+            .pool
+            .pick(&client_key)
+            .ok_or(CallError::Downstream(DownstreamError::Unavailable))?;
+        let target_url = websocket::to_websocket_url(&format!(
+            "{}{}",
+            backend.backend.url, route.forwarded_path
+        ));
+        let (upstream_socket, protocol) = websocket::connect(
+            &target_url,
+            self.into_header_map(&user_query),
+            &parts.headers,
+            &route.upstream,
+        )
+        .await
+        .map_err(|err| {
+            println!("Downstream error: {:?}", err);
+            CallError::Downstream(DownstreamError::DownstreamError {
+                response: Response::new(Body::from(format!("Downstream error: {}", err))),
+            })
+        })?;
+
+        let upgrade = match protocol {
+            Some(protocol) => upgrade.protocols(protocol.to_str().ok().map(|p| p.to_string())),
+            None => upgrade,
+        };
+        let upstream = route.upstream.clone();
+        Ok(upgrade.on_upgrade(move |socket| {
+            websocket::tunnel(socket, upstream_socket, upstream, rate_limit_key, backend)
+        }))
+    }
+
+    // gRPC calls are streamed both ways and limited per client and method,
+    // they are never retried since the request body is not buffered
+    pub async fn grpc_handler(&self, req: Request<Body>) -> Result<Response<Body>, CallError> {
+        let user_query: UserQuery = self.map(&req);
+        let route: ResolvedRoute = self
+            .resolve_route(&req, &user_query)
+            .map_err(CallError::Routing)?;
+        let method = grpc::method_name(&req).ok_or(CallError::Routing(RoutingError::NoRoute))?;
+
+        let client_key = self.client_key(&user_query);
+        let upstream: &Upstream = &route.upstream;
+        let identity = self
+            .authenticate(&req, &user_query, upstream)
+            .map_err(CallError::Authorization)?;
+        // a method limit takes precedence over the rules of the upstream
+        let facts = RequestFacts::new(&req, &user_query.client, &self.forwarding);
+        let (limiter, key_extractor) = match upstream.grpc.method_limiters.get(&method) {
+            Some(limiter) => (limiter, &upstream.key_extractor),
+            None => upstream.limit_for(&facts),
+        };
+        let rate_limit_key = identity
+            .as_ref()
+            .map(|identity| identity.id.clone())
+            .or_else(|| key_extractor.extract(req.headers(), &user_query.client))
+            .unwrap_or_else(|| client_key.clone());
+        let plan = identity
+            .as_ref()
+            .and_then(|identity| identity.plan.as_deref());
+        if !limiter.is_authorized_on_plan(&format!("{} {}", rate_limit_key, method), plan) {
             println!("Authorization error");
             return Err(CallError::Authorization(AuthorizationError::TooManyQueries));
         }
 
-        let proxy_reqwest = self
-            .client
-            .request(user_query.verb.to_method(), &self.original_url)
+        let backend: BackendGuard = upstream
+            .pool
+            .pick(&client_key)
+            .ok_or(CallError::Downstream(DownstreamError::Unavailable))?;
+        let target_url = format!("{}{}", backend.backend.url, route.forwarded_path);
+
+        // metadata travels as headers, all of them are relayed
+        let (parts, body) = req.into_parts();
+        let mut headers = HeaderMap::new();
+        for (name, value) in &parts.headers {
+            if !streaming::is_hop_by_hop(name) && name != HOST {
+                headers.append(name, value.clone());
+            }
+        }
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        self.forwarding.apply(&mut headers, &user_query.client);
+
+        let client: &Client = upstream.client.as_ref().unwrap_or(&self.client);
+        let request = client
+            .post(&target_url)
+            .version(Version::HTTP_2)
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(body.into_data_stream()));
+
+        let circuit_breaker = &upstream.circuit_breaker;
+        let Some(permit) = circuit_breaker.allow_request() else {
+            println!("Circuit open for upstream {}", upstream.name);
+            return Err(CallError::Downstream(DownstreamError::CircuitOpen));
+        };
+        let started = Instant::now();
+        let proxy_res = match route.timeouts.first_byte {
+            Some(first_byte) => tokio::time::timeout(first_byte, request.send())
+                .await
+                .map_or(Err(UpstreamError::FirstByteTimeout), |res| {
+                    res.map_err(UpstreamError::Request)
+                }),
+            None => request.send().await.map_err(UpstreamError::Request),
+        };
+        let success = matches!(&proxy_res, Ok(res) if !res.status().is_server_error());
+        permit.record(success, started.elapsed());
+
+        match proxy_res {
+            Ok(res) => Ok(grpc::into_response(res, backend)),
+            Err(err) if err.is_timeout() => {
+                println!("Downstream timeout: {:?}", err);
+                Err(CallError::Downstream(DownstreamError::Timeout))
+            }
+            Err(err) => {
+                println!("Downstream error: {:?}", err);
+                Err(CallError::Downstream(DownstreamError::Unavailable))
+            }
+        }
+    }
+
+    async fn forward(
+        &self,
+        route: &ResolvedRoute,
+        user_query: &UserQuery,
+        identity: Option<&Identity>,
+        revalidate: Option<HeaderValue>,
+        request_headers: &HeaderMap,
+    ) -> Result<Response<Body>, CallError> {
+        // held until the response body is relayed, to count outstanding requests
+        let backend: BackendGuard = route
+            .upstream
+            .pool
+            .pick(&self.client_key(user_query))
+            .ok_or(CallError::Downstream(DownstreamError::Unavailable))?;
+        let target_url = format!("{}{}", backend.backend.url, route.forwarded_path);
+
+        // an upstream with its own connection settings comes with its own client
+        let client: &Client = route.upstream.client.as_ref().unwrap_or(&self.client);
+        let mut request_builder = client
+            .request(user_query.verb.to_method(), &target_url)
             //must manage the body here
-            .headers(self.into_header_map(&user_query))
-            .build();
+            .headers(self.into_header_map(user_query));
+        if let Some(etag) = revalidate {
+            request_builder = request_builder.header(IF_NONE_MATCH, etag);
+        }
+        let proxy_reqwest = request_builder.build();
         // TODO: fix the request building, why use client?
         let request = proxy_reqwest.expect("Oups! building request failed");
 
-        let proxy_res = self.client.execute(request).await;
+        let circuit_breaker = &route.upstream.circuit_breaker;
+        let Some(permit) = circuit_breaker.allow_request() else {
+            println!("Circuit open for upstream {}", route.upstream.name);
+            return Err(CallError::Downstream(DownstreamError::CircuitOpen));
+        };
+        let started = Instant::now();
+        let execution = self.execute_with_retries(client, route, request, user_query);
+        let proxy_res = match route.timeouts.total {
+            Some(total) => tokio::time::timeout(total, execution)
+                .await
+                .unwrap_or(Err(UpstreamError::TotalTimeout)),
+            None => execution.await,
+        };
+        let success = matches!(&proxy_res, Ok(res) if !res.status().is_server_error());
+        permit.record(success, started.elapsed());
+
+        let cacheable = route.upstream.cache.is_some() && matches!(user_query.verb, Verb::GET);
         match proxy_res {
-            Ok(_) => Ok(()),
+            Ok(res) if cacheable => {
+                Self::cached_response(res, request_headers, route, identity, started, backend).await
+            }
+            Ok(res) => Ok(streaming::into_response(
+                res,
+                &route.timeouts,
+                started,
+                backend,
+            )),
+            Err(err) if err.is_timeout() => {
+                println!("Downstream timeout: {:?}", err);
+                Err(CallError::Downstream(DownstreamError::Timeout))
+            }
             Err(err) => {
                 println!("Downstream error: {:?}", err);
                 Err(CallError::Downstream(DownstreamError::DownstreamError {
@@ -46,9 +430,106 @@ impl<T> Proxy<Request<T>> for HttpProxy {
             }
         }
     }
-}
 
-impl HttpProxy {
+    // buffers and stores the responses the cache accepts, the others are streamed
+    async fn cached_response(
+        res: reqwest::Response,
+        request_headers: &HeaderMap,
+        route: &ResolvedRoute,
+        identity: Option<&Identity>,
+        started: Instant,
+        backend: BackendGuard,
+    ) -> Result<Response<Body>, CallError> {
+        let cache = route.upstream.cache.as_ref().expect("caching upstream");
+        let key = ResponseCache::key(&route.upstream.name, identity, &route.forwarded_path);
+        if res.status() == StatusCode::NOT_MODIFIED {
+            let ttl = cache::freshness(res.headers()).unwrap_or_default();
+            if let Some(cached) = cache.refresh(&key, request_headers, ttl) {
+                return Ok(cached.into_response(request_headers));
+            }
+        }
+        let Some(ttl) = cache.ttl(request_headers, res.status(), res.headers()) else {
+            return Ok(streaming::into_response(
+                res,
+                &route.timeouts,
+                started,
+                backend,
+            ));
+        };
+
+        let status = res.status();
+        let mut headers = HeaderMap::new();
+        for (name, value) in res.headers() {
+            if !streaming::is_hop_by_hop(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        let body = match route.timeouts.total {
+            Some(total) => {
+                let remaining = total.saturating_sub(started.elapsed());
+                tokio::time::timeout(remaining, res.bytes())
+                    .await
+                    .map_err(|_| CallError::Downstream(DownstreamError::Timeout))?
+            }
+            None => res.bytes().await,
+        };
+        let body = body.map_err(|err| {
+            println!("Downstream error: {:?}", err);
+            CallError::Downstream(DownstreamError::DownstreamError {
+                response: Response::new(Body::from(format!("Downstream error: {}", err))),
+            })
+        })?;
+        let cached = cache.store(&key, request_headers, status, headers, body, ttl);
+        Ok(cached.into_response(request_headers))
+    }
+
+    async fn execute_with_retries(
+        &self,
+        client: &Client,
+        route: &ResolvedRoute,
+        request: reqwest::Request,
+        user_query: &UserQuery,
+    ) -> Result<reqwest::Response, UpstreamError> {
+        let upstream: &Upstream = &route.upstream;
+        let retry: &RetryPolicy = &upstream.retry;
+        retry.record_request();
+        let retryable = RetryPolicy::is_retryable(
+            &user_query.verb,
+            user_query.header.contains_key(&QueryParams::IdempotencyKey),
+        );
+
+        let mut request = request;
+        let mut attempt: u32 = 0;
+        loop {
+            let retry_request = request.try_clone().filter(|_| retryable);
+            let res = match route.timeouts.first_byte {
+                Some(first_byte) => tokio::time::timeout(first_byte, client.execute(request))
+                    .await
+                    .map_or(Err(UpstreamError::FirstByteTimeout), |res| {
+                        res.map_err(UpstreamError::Request)
+                    }),
+                None => client
+                    .execute(request)
+                    .await
+                    .map_err(UpstreamError::Request),
+            };
+            match (res, retry_request) {
+                (Err(err), Some(next_request))
+                    if err.is_transient()
+                        && attempt < retry.config.max_retries
+                        && retry.try_acquire_retry() =>
+                {
+                    let delay = retry.backoff(attempt);
+                    println!("Retrying {} in {:?}: {}", upstream.name, delay, err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    request = next_request;
+                }
+                (res, _) => return res,
+            }
+        }
+    }
+
     fn into_header_map(&self, user_query: &UserQuery) -> HeaderMap {
         let mut header_map = HeaderMap::new();
         for (k, v) in &user_query.header {
@@ -58,13 +539,78 @@ impl HttpProxy {
                 HeaderValue::from_bytes(v.as_slice()).expect("Oups! in header value");
             header_map.append(header_name, header_value);
         }
+        self.forwarding.apply(&mut header_map, &user_query.client);
         return header_map;
     }
 
-    fn check_user_authorization(&self, user_query: &UserQuery) -> Result<(), AuthorizationError> {
-        let ip_opt: Option<&Vec<u8>> = user_query.header.get(&QueryParams::Ip);
-        if let Some(ip) = ip_opt {
-            return self.check_user_rate_limit(&String::from_utf8(ip.clone()).unwrap());
+    fn resolve_route<T>(
+        &self,
+        req: &Request<T>,
+        user_query: &UserQuery,
+    ) -> Result<ResolvedRoute, RoutingError> {
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        self.routing
+            .resolve(user_query.client.host.as_deref(), path_and_query)
+            .ok_or(RoutingError::NoRoute)
+    }
+
+    // the rate limit key, also used for consistent hashing
+    fn client_key(&self, user_query: &UserQuery) -> String {
+        match user_query.header.get(&QueryParams::Ip) {
+            Some(ip) => String::from_utf8_lossy(ip).to_string(),
+            None => user_query
+                .client
+                .ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    // None when the upstream does not authenticate its requests
+    fn authenticate<T>(
+        &self,
+        req: &Request<T>,
+        user_query: &UserQuery,
+        upstream: &Upstream,
+    ) -> Result<Option<Identity>, AuthorizationError> {
+        let identity = upstream
+            .authenticator
+            .as_ref()
+            .map(|authenticator| authenticator.authenticate(req, user_query))
+            .transpose();
+        if let Err(err) = &identity {
+            println!("Authentication error: {:?}", err);
+        }
+        identity
+    }
+
+    fn authorize(
+        &self,
+        key: Option<&str>,
+        plan: Option<&str>,
+        limiter: &RateLimiter,
+    ) -> Result<(), AuthorizationError> {
+        let result = self.check_user_authorization(key, plan, limiter);
+        if let Err(err) = &result {
+            println!("Authorization error: {:?}", err);
+        }
+        result
+    }
+
+    // the limiter and key are those of the first matching rule, or of the upstream,
+    // an authenticated identity replaces the key
+    fn check_user_authorization(
+        &self,
+        key: Option<&str>,
+        plan: Option<&str>,
+        limiter: &RateLimiter,
+    ) -> Result<(), AuthorizationError> {
+        if let Some(key) = key {
+            return self.check_user_rate_limit(limiter, &key.to_string(), plan);
         } else {
             Err(AuthorizationError::IpHeaderMissing)
         }
@@ -74,11 +620,13 @@ impl HttpProxy {
         let query_param_map: HashMap<QueryParams, Vec<u8>> = self.extract_headers(req);
         let verb: Verb = self.extract_verb(req);
         let uri: String = self.extract_uri(req);
+        let client: ClientInfo = self.extract_client_info(req);
 
         UserQuery {
             header: query_param_map,
             verb: verb,
             uri: uri,
+            client,
         }
     }
 
@@ -96,9 +644,46 @@ impl HttpProxy {
             query_param_map.insert(QueryParams::Ip, ip);
         }
 
+        //Forwarded, Via: kept so that the proxy appends to them
+        //Idempotency-Key: passed through, it also makes a request retryable
+        //Authorization: passed through, bearer tokens are also checked when the upstream uses JWT
+        for param in [
+            QueryParams::Forwarded,
+            QueryParams::Via,
+            QueryParams::IdempotencyKey,
+            QueryParams::Authorization,
+        ] {
+            if let Some(header) = req.headers().get(param.to_header_name_str()) {
+                query_param_map.insert(param, header.as_bytes().to_vec());
+            }
+        }
+
         query_param_map
     }
 
+    fn extract_client_info<T>(&self, req: &Request<T>) -> ClientInfo {
+        // only set when the server is started with connect info
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let host = req
+            .headers()
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .map(|host| host.to_string())
+            .or_else(|| req.uri().authority().map(|a| a.to_string()));
+        let proto = req
+            .extensions()
+            .get::<ListenerProto>()
+            .map(|ListenerProto(proto)| *proto)
+            .or(req.uri().scheme_str())
+            .unwrap_or("http")
+            .to_string();
+
+        ClientInfo { ip, host, proto }
+    }
+
     fn extract_verb<T>(&self, req: &Request<T>) -> Verb {
         match req.method().as_str() {
             "GET" => Verb::GET,
@@ -114,8 +699,13 @@ impl HttpProxy {
         req.uri().to_owned().to_string()
     }
 
-    fn check_user_rate_limit(&self, ip: &String) -> Result<(), AuthorizationError> {
-        if self.rate_limiter.is_authorized(ip) {
+    fn check_user_rate_limit(
+        &self,
+        limiter: &RateLimiter,
+        ip: &String,
+        plan: Option<&str>,
+    ) -> Result<(), AuthorizationError> {
+        if limiter.is_authorized_on_plan(ip, plan) {
             Ok(())
         } else {
             Err(AuthorizationError::TooManyQueries)
@@ -131,16 +721,27 @@ mod tests {
     use axum::body::Body;
     use axum::http::Request;
 
+    use crate::api::cache::{CacheConfig, ResponseCache};
+    use crate::api::forwarding::ForwardingConfig;
     use crate::api::http_proxy::HttpProxy;
+    use crate::api::model::{CallError, DownstreamError};
+    use crate::api::routing::{RoutingTable, Upstream};
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use crate::api::timeouts::Timeouts;
+    use axum::Router;
+    use axum::routing::get;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_simple_proxy_handler() {
         let mut rate_limiter = RateLimiter::new(2, 1);
         let client = reqwest::Client::new();
         let http_proxy = HttpProxy {
-            rate_limiter,
+            routing: Arc::new(RoutingTable::single("https://www.google.com", rate_limiter)),
             client,
-            original_url: "https://www.google.com".to_string(),
+            forwarding: ForwardingConfig::default(),
         };
 
         assert!(
@@ -163,11 +764,77 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_first_byte_timeout() {
+        let app = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                "too late"
+            }),
+        );
+        let mut upstream =
+            Upstream::new("slow", &spawn_upstream(app).await, RateLimiter::new(10, 1));
+        upstream.timeouts.first_byte = Some(Duration::from_millis(50));
+        let mut http_proxy = proxy_to(upstream);
+        http_proxy.client = Timeouts::with_defaults().client_builder().build().unwrap();
+
+        let req = Request::get("/slow")
+            .header("x-forwarded-for", "1.0.0.0")
+            .body(Body::empty())
+            .unwrap();
+        let res = http_proxy.proxy_handler(req).await;
+        assert!(matches!(
+            res,
+            Err(CallError::Downstream(DownstreamError::Timeout))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cached_get_skips_upstream_and_quota() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let app = Router::new().route(
+            "/pets",
+            get(move || async move {
+                upstream_hits.fetch_add(1, Ordering::SeqCst);
+                ([("cache-control", "max-age=60"), ("etag", "\"v1\"")], "[]")
+            }),
+        );
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(1, 60));
+        upstream.cache = Some(ResponseCache::new(CacheConfig {
+            bypass_rate_limit: true,
+            ..CacheConfig::default()
+        }));
+        let http_proxy = proxy_to(upstream);
+        let get_pets = |etag: Option<&str>| {
+            let mut req = Request::get("/pets").header("x-forwarded-for", "1.0.0.0");
+            if let Some(etag) = etag {
+                req = req.header("if-none-match", etag);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        for _ in 0..3 {
+            let res = http_proxy.proxy_handler(get_pets(None)).await.ok().unwrap();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+            assert_eq!(body.unwrap(), "[]");
+        }
+        let res = http_proxy
+            .proxy_handler(get_pets(Some("\"v1\"")))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(res.status(), 304);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
     fn create_body() -> Body {
         let body_json = "{\"key\": \"value\"}".to_string();
         Body::new(body_json)
     }
-    
+
     fn generate_request(body: Body) -> Request<Body> {
         Request::get("https://www.google.com")
             .header("x-forwarded-for", "1.0.0.0")
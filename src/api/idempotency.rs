@@ -0,0 +1,457 @@
+use crate::api::auth::Identity;
+use crate::api::streaming::BufferedResponse;
+use axum::body::Body;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::{HeaderValue, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub ttl: Duration, // how long a response is replayed for
+    pub max_body_size: usize, // larger responses are relayed but not stored
+    pub max_entries: usize,   // the oldest responses are forgotten first
+    pub max_total_size: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_body_size: 1024 * 1024,
+            max_entries: 10_000,
+            max_total_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+enum Entry {
+    InFlight,
+    Completed {
+        response: BufferedResponse,
+        expires: Instant,
+        sequence: u64,
+    },
+    Sent {
+        expires: Instant, // the request reached the upstream, its response was not stored
+    },
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self {
+            Entry::InFlight => false,
+            Entry::Completed { expires, .. } | Entry::Sent { expires } => *expires <= now,
+        }
+    }
+}
+
+#[derive(Default)]
+struct IdempotencyState {
+    entries: HashMap<String, Entry>,
+    order: VecDeque<(String, u64)>, // completed responses, oldest first
+    count: usize,                   // of the completed responses
+    size: usize,                    // of their bodies
+    sequence: u64,
+    begins: usize, // expired responses are purged every PURGE_EVERY requests
+}
+
+const PURGE_EVERY: usize = 1000;
+
+impl IdempotencyState {
+    fn purge(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+        let order = std::mem::take(&mut self.order);
+        self.order = order
+            .into_iter()
+            .filter(|(key, sequence)| self.is_current(key, *sequence))
+            .collect();
+        self.count = self.order.len();
+        self.size = self.order.iter().map(|(key, _)| self.body_size(key)).sum();
+    }
+
+    // false once the response was replaced or removed
+    fn is_current(&self, key: &str, sequence: u64) -> bool {
+        match self.entries.get(key) {
+            Some(Entry::Completed { sequence: s, .. }) => *s == sequence,
+            _ => false,
+        }
+    }
+
+    fn body_size(&self, key: &str) -> usize {
+        match self.entries.get(key) {
+            Some(Entry::Completed { response, .. }) => response.body.len(),
+            _ => 0,
+        }
+    }
+
+    // an expired response replaced by a new request no longer counts
+    fn insert(&mut self, key: &str, entry: Entry) {
+        if let Some(Entry::Completed { response, .. }) = self.entries.insert(key.to_string(), entry)
+        {
+            self.count -= 1;
+            self.size -= response.body.len();
+        }
+    }
+
+    fn complete(&mut self, key: &str, response: BufferedResponse, ttl: Duration) {
+        self.sequence += 1;
+        self.count += 1;
+        self.size += response.body.len();
+        self.order.push_back((key.to_string(), self.sequence));
+        self.entries.insert(
+            key.to_string(),
+            Entry::Completed {
+                response,
+                expires: Instant::now() + ttl,
+                sequence: self.sequence,
+            },
+        );
+    }
+
+    fn evict(&mut self, config: &IdempotencyConfig) {
+        while self.count > config.max_entries || self.size > config.max_total_size {
+            let Some((key, sequence)) = self.order.pop_front() else {
+                break;
+            };
+            if self.is_current(&key, sequence) {
+                self.count -= 1;
+                self.size -= self.body_size(&key);
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+pub enum Begin {
+    Proceed(IdempotencyGuard), // first time this key is seen
+    Replay(Response<Body>),
+    Conflict, // the same key is still being processed
+    Sent,     // the upstream may have acted on the first request, it cannot be sent again
+}
+
+// Remembers the responses of non-idempotent requests sent with an Idempotency-Key
+pub struct IdempotencyStore {
+    pub config: IdempotencyConfig,
+    state: Arc<Mutex<IdempotencyState>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(config: IdempotencyConfig) -> IdempotencyStore {
+        IdempotencyStore {
+            config,
+            state: Arc::new(Mutex::new(IdempotencyState::default())),
+        }
+    }
+
+    // stored responses outlive a reload, duplicates keep being answered with them
+    pub fn carry_over(&mut self, previous: &IdempotencyStore) {
+        self.state = previous.state.clone();
+    }
+
+    // keys are scoped to the endpoint and to the authenticated identity,
+    // or to the client address when the upstream does not authenticate
+    pub fn key(
+        identity: Option<&Identity>,
+        client_ip: Option<IpAddr>,
+        method: &str,
+        path: &str,
+        idempotency_key: &[u8],
+    ) -> String {
+        let client = match (identity, client_ip) {
+            (Some(identity), _) => format!("identity: {}", identity.id),
+            (None, Some(ip)) => format!("ip: {}", ip),
+            (None, None) => "unknown".to_string(),
+        };
+        format!(
+            "{} {} {} {}",
+            client,
+            method,
+            path,
+            String::from_utf8_lossy(idempotency_key)
+        )
+    }
+
+    pub fn begin(&self, key: &str) -> Begin {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.begins += 1;
+        if state.begins.is_multiple_of(PURGE_EVERY) {
+            state.purge(now);
+        }
+        match state
+            .entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+        {
+            Some(Entry::InFlight) => Begin::Conflict,
+            Some(Entry::Sent { .. }) => Begin::Sent,
+            Some(Entry::Completed { response, .. }) => {
+                let mut replay = response.clone().into_response();
+                replay
+                    .headers_mut()
+                    .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+                Begin::Replay(replay)
+            }
+            None => {
+                state.insert(key, Entry::InFlight);
+                Begin::Proceed(IdempotencyGuard {
+                    key: key.to_string(),
+                    state: self.state.clone(),
+                    config: self.config.clone(),
+                })
+            }
+        }
+    }
+}
+
+// Marks the key as sent when dropped before a response is stored, duplicates are
+// then refused until the TTL passes instead of reaching the upstream a second time
+pub struct IdempotencyGuard {
+    key: String,
+    state: Arc<Mutex<IdempotencyState>>,
+    config: IdempotencyConfig,
+}
+
+impl IdempotencyGuard {
+    // stores the response when its body fits the size limit, a larger one is relayed
+    pub async fn complete(self, response: Response<Body>) -> Response<Body> {
+        let too_large = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|length| length > self.config.max_body_size);
+        if too_large {
+            return response;
+        }
+        match BufferedResponse::read(response, self.config.max_body_size).await {
+            Ok(buffered) => {
+                let mut state = self.state.lock().unwrap();
+                state.complete(&self.key, buffered.clone(), self.config.ttl);
+                state.evict(&self.config);
+                buffered.into_response()
+            }
+            Err(Some(response)) => response,
+            Err(None) => {
+                let mut response = Response::new(Body::from("Bad Gateway"));
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                response
+            }
+        }
+    }
+
+    // the request never left the proxy, it can be sent again
+    pub fn release(self) {
+        self.state.lock().unwrap().entries.remove(&self.key);
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.entries.get(&self.key), Some(Entry::InFlight)) {
+            let expires = Instant::now() + self.config.ttl;
+            state
+                .entries
+                .insert(self.key.clone(), Entry::Sent { expires });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::api_keys::{ApiKey, ApiKeySource, ApiKeyStore, KeyStatus};
+    use crate::api::auth::Authenticator;
+    use crate::api::model::{CallError, DownstreamError, IdempotencyError};
+    use crate::api::proxy::Proxy;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use crate::engine::rate_limiter::RateLimiter;
+    use axum::Router;
+    use axum::http::Request;
+    use axum::routing::post;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_replay_and_concurrent_duplicate() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let upstream_created = created.clone();
+        let app = Router::new().route(
+            "/pets",
+            post(move || async move {
+                let id = upstream_created.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                format!("{{\"id\": {}}}", id)
+            }),
+        );
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(10, 60));
+        upstream.idempotency = Some(IdempotencyStore::new(IdempotencyConfig::default()));
+        let http_proxy = proxy_to(upstream);
+        let (first, duplicate) = tokio::join!(http_proxy.proxy_handler(create_pet()), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            http_proxy.proxy_handler(create_pet()).await
+        });
+        assert!(matches!(
+            duplicate,
+            Err(CallError::Idempotency(IdempotencyError::Conflict))
+        ));
+        let first = axum::body::to_bytes(first.ok().unwrap().into_body(), usize::MAX).await;
+
+        let replay = http_proxy.proxy_handler(create_pet()).await.ok().unwrap();
+        assert_eq!(replay.headers()[REPLAYED_HEADER], "true");
+        let replay = axum::body::to_bytes(replay.into_body(), usize::MAX).await;
+        assert_eq!(replay.unwrap(), first.unwrap());
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_identities_do_not_share_keys() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let upstream_created = created.clone();
+        let app = Router::new().route(
+            "/pets",
+            post(move || async move {
+                let id = upstream_created.fetch_add(1, Ordering::SeqCst);
+                format!("{{\"id\": {}}}", id)
+            }),
+        );
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(10, 60));
+        upstream.idempotency = Some(IdempotencyStore::new(IdempotencyConfig::default()));
+        let entry = |id: &str| ApiKey {
+            id: id.to_string(),
+            owner: id.to_string(),
+            status: KeyStatus::Active,
+            plan: None,
+        };
+        upstream.authenticator = Some(Authenticator::ApiKey(Arc::new(ApiKeyStore {
+            source: ApiKeySource::Header("x-api-key".to_string()),
+            keys: HashMap::from([
+                (ApiKeyStore::hash("secret-acme"), entry("acme")),
+                (ApiKeyStore::hash("secret-globex"), entry("globex")),
+            ]),
+        })));
+        let http_proxy = proxy_to(upstream);
+        // the same address and the same key, sent by two tenants
+        let create_pet = |api_key: &str| {
+            Request::post("/pets")
+                .header("x-forwarded-for", "1.0.0.0")
+                .header("x-api-key", api_key)
+                .header("idempotency-key", "create-rex")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let acme = http_proxy.proxy_handler(create_pet("secret-acme")).await;
+        let globex = http_proxy.proxy_handler(create_pet("secret-globex")).await;
+        let globex = globex.ok().unwrap();
+        assert!(!globex.headers().contains_key(REPLAYED_HEADER));
+        let acme = axum::body::to_bytes(acme.ok().unwrap().into_body(), usize::MAX).await;
+        let globex = axum::body::to_bytes(globex.into_body(), usize::MAX).await;
+        assert_ne!(acme.unwrap(), globex.unwrap());
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_response_replayed() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let upstream_created = created.clone();
+        let app = Router::new().route(
+            "/pets",
+            post(move || async move {
+                let id = upstream_created.fetch_add(1, Ordering::SeqCst);
+                let chunks = vec!["{\"id\": ".to_string(), format!("{}}}", id)];
+                Body::from_stream(futures::stream::iter(
+                    chunks.into_iter().map(Ok::<_, std::io::Error>),
+                ))
+            }),
+        );
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(10, 60));
+        upstream.idempotency = Some(IdempotencyStore::new(IdempotencyConfig::default()));
+        let http_proxy = proxy_to(upstream);
+
+        let first = http_proxy.proxy_handler(create_pet()).await.ok().unwrap();
+        assert!(!first.headers().contains_key(CONTENT_LENGTH));
+        let first = axum::body::to_bytes(first.into_body(), usize::MAX).await;
+        let replay = http_proxy.proxy_handler(create_pet()).await.ok().unwrap();
+        assert_eq!(replay.headers()[REPLAYED_HEADER], "true");
+        let replay = axum::body::to_bytes(replay.into_body(), usize::MAX).await;
+        assert_eq!(replay.unwrap(), first.unwrap());
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_request_not_sent_again() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let upstream_created = created.clone();
+        let app = Router::new().route(
+            "/pets",
+            post(move || async move {
+                upstream_created.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                "{\"id\": 0}"
+            }),
+        );
+        let mut upstream =
+            Upstream::new("pets", &spawn_upstream(app).await, RateLimiter::new(10, 60));
+        upstream.timeouts.total = Some(Duration::from_millis(100));
+        upstream.idempotency = Some(IdempotencyStore::new(IdempotencyConfig::default()));
+        let http_proxy = proxy_to(upstream);
+
+        assert!(matches!(
+            http_proxy.proxy_handler(create_pet()).await,
+            Err(CallError::Downstream(DownstreamError::Timeout))
+        ));
+        // the upstream may have created it, the retry must not create it again
+        assert!(matches!(
+            http_proxy.proxy_handler(create_pet()).await,
+            Err(CallError::Idempotency(IdempotencyError::Sent))
+        ));
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+    }
+
+    fn create_pet() -> Request<Body> {
+        Request::post("/pets")
+            .header("x-forwarded-for", "1.0.0.0")
+            .header("idempotency-key", "create-rex")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_oldest_responses_evicted() {
+        let store = IdempotencyStore::new(IdempotencyConfig {
+            max_entries: 2,
+            ..IdempotencyConfig::default()
+        });
+        let complete = |key: &'static str| {
+            let Begin::Proceed(guard) = store.begin(key) else {
+                panic!("expected a new key");
+            };
+            let response = Response::builder()
+                .header(CONTENT_LENGTH, key.len())
+                .body(Body::from(key))
+                .unwrap();
+            guard.complete(response)
+        };
+        complete("order-1").await;
+        complete("order-2").await;
+        complete("order-3").await;
+
+        assert!(matches!(store.begin("order-3"), Begin::Replay(_)));
+        assert!(matches!(store.begin("order-2"), Begin::Replay(_)));
+        assert!(matches!(store.begin("order-1"), Begin::Proceed(_)));
+        let state = store.state.lock().unwrap();
+        assert_eq!((state.count, state.size), (2, 14));
+    }
+}
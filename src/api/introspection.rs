@@ -0,0 +1,333 @@
+use crate::api::auth::Identity;
+use crate::engine::rate_limiter::RateLimiter;
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+// The identity found by introspection, set as a request extension before the
+// authenticator of the upstream runs
+#[derive(Clone, Debug)]
+pub struct Introspected(pub Identity);
+
+// The part of an RFC 7662 response the proxy uses
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    client_id: Option<String>,
+    sub: Option<String>,
+    exp: Option<i64>,
+    #[serde(flatten)]
+    claims: HashMap<String, Value>,
+}
+
+#[derive(Clone, Debug)]
+pub enum IntrospectionError {
+    TooManyLookups,      // the client sent too many tokens the proxy did not know
+    Unavailable(String), // the endpoint could not answer
+}
+
+impl fmt::Display for IntrospectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntrospectionError::TooManyLookups => write!(f, "too many lookups"),
+            IntrospectionError::Unavailable(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+type Lookup = Arc<OnceCell<Result<Option<Identity>, IntrospectionError>>>;
+
+// Asks an authorization server whether opaque tokens are active, and remembers
+// the answers for a while so that each token is only sent now and then
+#[derive(Clone)]
+pub struct Introspector {
+    pub endpoint: String,
+    pub client_id: Option<String>, // basic auth of the proxy to the endpoint
+    pub client_secret: Option<String>,
+    pub active_ttl: Duration, // shortened for tokens expiring sooner
+    pub inactive_ttl: Duration,
+    pub plan_claim: Option<String>,
+    pub limiter: RateLimiter, // lookups of tokens not in the cache, by client IP
+    client: reqwest::Client,
+    cache: Arc<DashMap<String, (Option<Identity>, Instant)>>, // by token hash, until the instant
+    inserts: Arc<AtomicUsize>,
+    in_flight: Arc<Mutex<HashMap<String, Lookup>>>, // by token hash
+}
+
+const PURGE_EVERY: usize = 1000;
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+impl Introspector {
+    pub fn new(endpoint: &str, timeout: Duration) -> Result<Introspector, String> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Introspector {
+            endpoint: endpoint.to_string(),
+            client_id: None,
+            client_secret: None,
+            active_ttl: Duration::from_secs(60),
+            inactive_ttl: Duration::from_secs(10),
+            plan_claim: None,
+            limiter: RateLimiter::new(60, 60),
+            client,
+            cache: Arc::new(DashMap::new()),
+            inserts: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    // the answers of the same endpoint stay valid across a reload
+    pub fn carry_over(&mut self, previous: &Introspector) {
+        self.limiter.carry_over(&previous.limiter);
+        if self.endpoint == previous.endpoint && self.plan_claim == previous.plan_claim {
+            self.cache = previous.cache.clone();
+            self.inserts = previous.inserts.clone();
+        }
+    }
+
+    // None for inactive tokens, errors are not cached, concurrent lookups
+    // of the same token share one call to the endpoint
+    pub async fn introspect(
+        &self,
+        token: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Option<Identity>, IntrospectionError> {
+        let key = hex::encode(Sha256::digest(token.as_bytes()));
+        if let Some(cached) = self.cache.get(&key)
+            && cached.1 > Instant::now()
+        {
+            return Ok(cached.0.clone());
+        }
+        let client_key = client_ip.map_or("unknown".to_string(), |ip| ip.to_string());
+        if !self.limiter.is_authorized(&client_key) {
+            return Err(IntrospectionError::TooManyLookups);
+        }
+
+        let lookup: Lookup = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = lookup
+            .get_or_init(|| async {
+                let result = self.call(token).await;
+                if let Ok((identity, ttl)) = &result {
+                    self.remember(key.clone(), identity.clone(), *ttl);
+                }
+                result
+                    .map(|(identity, _)| identity)
+                    .map_err(IntrospectionError::Unavailable)
+            })
+            .await
+            .clone();
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if in_flight
+                .get(&key)
+                .is_some_and(|current| Arc::ptr_eq(current, &lookup))
+            {
+                in_flight.remove(&key);
+            }
+        }
+        result
+    }
+
+    // the identity and how long it can be remembered for
+    async fn call(&self, token: &str) -> Result<(Option<Identity>, Duration), String> {
+        let mut request = self
+            .client
+            .post(&self.endpoint)
+            .form(&[("token", token), ("token_type_hint", "access_token")]);
+        if let Some(client_id) = &self.client_id {
+            request = request.basic_auth(client_id, self.client_secret.as_ref());
+        }
+        let mut response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_RESPONSE_SIZE as u64)
+        {
+            return Err("response too large".to_string());
+        }
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                return Err("response too large".to_string());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let response: IntrospectionResponse =
+            serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+
+        let now = chrono::Utc::now().timestamp();
+        let identity = response
+            .client_id
+            .clone()
+            .or(response.sub.clone())
+            .filter(|_| response.active && response.exp.is_none_or(|exp| exp > now))
+            .map(|id| Identity {
+                id,
+                plan: self.plan_claim.as_ref().and_then(|claim| {
+                    match response.claims.get(claim)? {
+                        Value::String(plan) => Some(plan.clone()),
+                        _ => None,
+                    }
+                }),
+            });
+        let ttl = match (&identity, response.exp) {
+            (Some(_), Some(exp)) => self.active_ttl.min(Duration::from_secs((exp - now) as u64)),
+            (Some(_), None) => self.active_ttl,
+            (None, _) => self.inactive_ttl,
+        };
+        Ok((identity, ttl))
+    }
+
+    fn remember(&self, key: String, identity: Option<Identity>, ttl: Duration) {
+        let now = Instant::now();
+        if self
+            .inserts
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(PURGE_EVERY)
+        {
+            self.cache.retain(|_, (_, until)| *until > now);
+        }
+        self.cache.insert(key, (identity, now + ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::spawn_upstream;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_introspect_and_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/introspect",
+                post(
+                    |State(calls): State<Arc<AtomicUsize>>,
+                     Form(form): Form<HashMap<String, String>>| async move {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        Json(match form["token"].as_str() {
+                            "opaque-1" => serde_json::json!({
+                                "active": true, "client_id": "acme", "sub": "user-7", "plan": "pro"
+                            }),
+                            _ => serde_json::json!({ "active": false }),
+                        })
+                    },
+                ),
+            )
+            .with_state(calls.clone());
+        let endpoint = format!("{}/introspect", spawn_upstream(app).await);
+        let mut introspector = Introspector::new(&endpoint, Duration::from_secs(2)).unwrap();
+        introspector.plan_claim = Some("plan".to_string());
+
+        let identity = introspector
+            .introspect("opaque-1", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(identity.id, "acme");
+        assert_eq!(identity.plan.as_deref(), Some("pro"));
+        assert!(
+            introspector
+                .introspect("opaque-1", None)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            introspector
+                .introspect("revoked", None)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            introspector
+                .introspect("revoked", None)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let unreachable = Introspector::new("http://127.0.0.1:9/", Duration::from_secs(2)).unwrap();
+        assert!(unreachable.introspect("opaque-1", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookups_merged_limited_and_kept() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/introspect",
+                post(
+                    |State(calls): State<Arc<AtomicUsize>>,
+                     Form(form): Form<HashMap<String, String>>| async move {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        match form["token"].as_str() {
+                            "huge" => "x".repeat(MAX_RESPONSE_SIZE + 1),
+                            _ => r#"{"active": true, "client_id": "acme"}"#.to_string(),
+                        }
+                    },
+                ),
+            )
+            .with_state(calls.clone());
+        let endpoint = format!("{}/introspect", spawn_upstream(app).await);
+        let mut introspector = Introspector::new(&endpoint, Duration::from_secs(2)).unwrap();
+        introspector.limiter = RateLimiter::new(3, 60);
+        let client: Option<IpAddr> = Some("192.168.1.10".parse().unwrap());
+
+        let (first, second) = tokio::join!(
+            introspector.introspect("opaque-1", client),
+            introspector.introspect("opaque-1", client)
+        );
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            introspector.introspect("huge", client).await,
+            Err(IntrospectionError::Unavailable(_))
+        ));
+        assert!(matches!(
+            introspector.introspect("guess", client).await,
+            Err(IntrospectionError::TooManyLookups)
+        ));
+        let other: Option<IpAddr> = Some("192.168.1.11".parse().unwrap());
+        assert!(introspector.introspect("guess", other).await.is_ok());
+
+        let mut reloaded = Introspector::new(&endpoint, Duration::from_secs(2)).unwrap();
+        reloaded.carry_over(&introspector);
+        let calls_before = calls.load(Ordering::Relaxed);
+        assert!(
+            reloaded
+                .introspect("opaque-1", client)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(calls.load(Ordering::Relaxed), calls_before);
+    }
+}
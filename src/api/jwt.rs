@@ -0,0 +1,170 @@
+use crate::api::auth::{Identity, bearer_token};
+use crate::api::model::AuthorizationError;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+struct JwtKey {
+    id: Option<String>, // the kid of the JWKS entry
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+// Checks bearer tokens against the keys of a JWKS file, with HS256, RS256 or ES256
+pub struct JwtValidator {
+    keys: Vec<JwtKey>,
+    pub issuer: Option<String>,   // None accepts any iss
+    pub audience: Option<String>, // None accepts any aud
+    pub leeway: Duration,         // clock skew allowed on exp and nbf
+    pub key_claim: String,        // becomes the rate-limit key, e.g. sub or org_id
+    pub plan_claim: Option<String>,
+}
+
+impl JwtValidator {
+    pub fn new(jwks: &JwkSet) -> Result<JwtValidator, String> {
+        let mut keys = vec![];
+        for jwk in &jwks.keys {
+            let id = jwk.common.key_id.clone();
+            let name = id.as_deref().unwrap_or("without kid");
+            let algorithm = match (jwk.common.key_algorithm, &jwk.algorithm) {
+                (Some(KeyAlgorithm::HS256), AlgorithmParameters::OctetKey(_))
+                | (None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+                (Some(KeyAlgorithm::RS256), AlgorithmParameters::RSA(_))
+                | (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+                (Some(KeyAlgorithm::ES256) | None, AlgorithmParameters::EllipticCurve(ec))
+                    if ec.curve == EllipticCurve::P256 =>
+                {
+                    Algorithm::ES256
+                }
+                _ => {
+                    return Err(format!(
+                        "key {}: only HS256, RS256 and ES256 are supported",
+                        name
+                    ));
+                }
+            };
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("key {}: {}", name, e))?;
+            keys.push(JwtKey { id, algorithm, key });
+        }
+        if keys.is_empty() {
+            return Err("no keys".to_string());
+        }
+        Ok(JwtValidator {
+            keys,
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(30),
+            key_claim: "sub".to_string(),
+            plan_claim: None,
+        })
+    }
+
+    // the value of the Authorization header
+    pub fn authenticate(
+        &self,
+        authorization: Option<&[u8]>,
+    ) -> Result<Identity, AuthorizationError> {
+        let token = bearer_token(authorization).ok_or(AuthorizationError::Unauthenticated)?;
+        let claims = self.validate(token).map_err(|err| {
+            println!("Invalid token: {}", err);
+            AuthorizationError::Unauthenticated
+        })?;
+        let claim = |name: &str| match claims.get(name)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        };
+        Ok(Identity {
+            id: claim(&self.key_claim).ok_or(AuthorizationError::Unauthenticated)?,
+            plan: self.plan_claim.as_deref().and_then(claim),
+        })
+    }
+
+    fn validate(&self, token: &str) -> Result<HashMap<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.set_required_spec_claims(&["exp"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        // a token naming its key is only checked against that key
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg && (header.kid.is_none() || key.id == header.kid)
+        });
+        let mut last_error = format!("no {:?} key matches", header.alg);
+        for candidate in candidates {
+            match jsonwebtoken::decode(token, &candidate.key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(err) => last_error = err.to_string(),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_tokens() {
+        // the secret below, base64url encoded
+        let secret = "c2VjcmV0LW9mLWF0LWxlYXN0LTMyLWJ5dGVzLWxvbmch";
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [{ "kty": "oct", "kid": "hmac-1", "k": secret }]
+        }))
+        .unwrap();
+        let mut validator = JwtValidator::new(&jwks).unwrap();
+        validator.issuer = Some("https://auth.example.com".to_string());
+        validator.key_claim = "org_id".to_string();
+        validator.plan_claim = Some("plan".to_string());
+        let now = chrono::Utc::now().timestamp();
+        let sign = |claims: Value| {
+            let mut header = Header::new(Algorithm::HS256);
+            header.kid = Some("hmac-1".to_string());
+            let key = EncodingKey::from_secret(b"secret-of-at-least-32-bytes-long!");
+            let token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+            format!("Bearer {}", token)
+        };
+
+        let valid = sign(json!({
+            "iss": "https://auth.example.com", "exp": now + 60, "org_id": "acme", "plan": "pro"
+        }));
+        let identity = validator.authenticate(Some(valid.as_bytes())).unwrap();
+        assert_eq!(identity.id, "acme");
+        assert_eq!(identity.plan.as_deref(), Some("pro"));
+
+        let expired =
+            sign(json!({ "iss": "https://auth.example.com", "exp": now - 120, "org_id": "acme" }));
+        let not_yet = sign(json!({
+            "iss": "https://auth.example.com", "exp": now + 600, "nbf": now + 300, "org_id": "acme"
+        }));
+        let other_issuer =
+            sign(json!({ "iss": "https://evil.example.com", "exp": now + 60, "org_id": "acme" }));
+        let no_key_claim = sign(json!({ "iss": "https://auth.example.com", "exp": now + 60 }));
+        for token in [
+            expired,
+            not_yet,
+            other_issuer,
+            no_key_claim,
+            "Bearer x.y.z".to_string(),
+        ] {
+            assert!(
+                validator.authenticate(Some(token.as_bytes())).is_err(),
+                "{}",
+                token
+            );
+        }
+        assert!(validator.authenticate(None).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use crate::api::forwarding::ClientInfo;
+use axum::http::HeaderMap;
+use serde::Deserialize;
+
+// What requests are counted by: each distinct key gets its own quota
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyExtractor {
+    #[default]
+    ForwardedFor, // the X-Forwarded-For header as sent to the proxy
+    ClientIp,       // the address of the connection
+    Header(String), // any request header, e.g. x-api-key
+}
+
+impl KeyExtractor {
+    // None when the request does not carry the key
+    pub fn extract(&self, headers: &HeaderMap, client: &ClientInfo) -> Option<String> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+        match self {
+            KeyExtractor::ForwardedFor => header("x-forwarded-for"),
+            KeyExtractor::ClientIp => client.ip.map(|ip| ip.to_string()),
+            KeyExtractor::Header(name) => header(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_extract_keys() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("key-1"));
+        let client = ClientInfo {
+            ip: Some("10.0.0.1".parse().unwrap()),
+            ..ClientInfo::default()
+        };
+
+        let api_key = KeyExtractor::Header("X-Api-Key".to_string());
+        assert_eq!(api_key.extract(&headers, &client).as_deref(), Some("key-1"));
+        assert_eq!(
+            KeyExtractor::ClientIp.extract(&headers, &client).as_deref(),
+            Some("10.0.0.1")
+        );
+        assert_eq!(KeyExtractor::ForwardedFor.extract(&headers, &client), None);
+    }
+}
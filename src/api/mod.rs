@@ -1,3 +1,26 @@
+pub mod api_keys;
+pub mod auth;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod coalescing;
+pub mod forwarding;
+pub mod grpc;
 pub mod http_proxy;
+pub mod idempotency;
+pub mod introspection;
+pub mod jwt;
+pub mod key_extractor;
 pub mod model;
-pub mod proxy;
\ No newline at end of file
+pub mod protocol;
+pub mod proxy;
+pub mod retry;
+pub mod routing;
+pub mod rules;
+pub mod signatures;
+pub mod streaming;
+#[cfg(test)]
+pub mod testing;
+pub mod timeouts;
+pub mod upstream_pool;
+pub mod upstream_tls;
+pub mod websocket;
\ No newline at end of file
@@ -1,3 +1,4 @@
+use crate::api::forwarding::ClientInfo;
 use axum::body::Body;
 use axum::response::Response;
 use reqwest::Method;
@@ -54,13 +55,27 @@ impl fmt::Display for AppError {
 pub enum CallError {
     Authorization(AuthorizationError),
     Technical(TechnicalError),
-    Downstream(DownstreamError)
+    Downstream(DownstreamError),
+    Routing(RoutingError),
+    Idempotency(IdempotencyError),
 } 
 
 #[derive(Debug)]
 pub enum AuthorizationError {
     TooManyQueries,
-    IpHeaderMissing
+    IpHeaderMissing,
+    Unauthenticated, // missing, unknown or revoked credentials
+}
+
+#[derive(Debug)]
+pub enum RoutingError {
+    NoRoute,
+}
+
+#[derive(Debug)]
+pub enum IdempotencyError {
+    Conflict, // a request with the same key is still in flight
+    Sent,     // a request with the same key got no stored response
 }
 
 #[derive(Debug)]
@@ -72,6 +87,44 @@ pub enum DownstreamError {
     DownstreamError {
         response: Response<Body>,
     },
+    Unavailable, // no healthy backend to send the request to
+    CircuitOpen,
+    Timeout,
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Request(reqwest::Error),
+    FirstByteTimeout,
+    TotalTimeout,
+}
+
+impl UpstreamError {
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            UpstreamError::Request(err) => err.is_timeout(),
+            UpstreamError::FirstByteTimeout | UpstreamError::TotalTimeout => true,
+        }
+    }
+
+    // worth retrying on an idempotent request
+    pub fn is_transient(&self) -> bool {
+        match self {
+            UpstreamError::Request(err) => err.is_connect() || err.is_timeout(),
+            UpstreamError::FirstByteTimeout => true,
+            UpstreamError::TotalTimeout => false,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Request(err) => write!(f, "{}", err),
+            UpstreamError::FirstByteTimeout => write!(f, "no response from upstream in time"),
+            UpstreamError::TotalTimeout => write!(f, "request to upstream took too long"),
+        }
+    }
 }
 
 
@@ -84,12 +137,20 @@ pub enum QueryIp {
 #[derive(PartialEq, Eq, Hash, Debug)]
 pub enum QueryParams {
     Ip,
+    Forwarded,
+    Via,
+    IdempotencyKey,
+    Authorization,
 }
 
 impl QueryParams {
     pub fn to_header_name_str(&self) -> String {
         match self {
             QueryParams::Ip => "x-forwarded-for".to_string(),
+            QueryParams::Forwarded => "forwarded".to_string(),
+            QueryParams::Via => "via".to_string(),
+            QueryParams::IdempotencyKey => "idempotency-key".to_string(),
+            QueryParams::Authorization => "authorization".to_string(),
         }
     }
 }
@@ -99,4 +160,5 @@ pub struct UserQuery {
     pub header: HashMap<QueryParams, Vec<u8>>,
     pub verb: Verb,
     pub uri: String,
+    pub client: ClientInfo,
 }
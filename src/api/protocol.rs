@@ -0,0 +1,96 @@
+use reqwest::ClientBuilder;
+use serde::Deserialize;
+
+// The HTTP version spoken to an upstream
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Auto, // negotiated with ALPN over TLS, HTTP/1.1 on plain connections
+    Http1,
+    Http2, // h2 over TLS, h2c with prior knowledge on plain connections
+}
+
+impl UpstreamProtocol {
+    pub fn configure(&self, builder: ClientBuilder) -> ClientBuilder {
+        match self {
+            UpstreamProtocol::Auto => builder,
+            UpstreamProtocol::Http1 => builder.http1_only(),
+            UpstreamProtocol::Http2 => builder.http2_prior_knowledge(),
+        }
+    }
+
+    // offered in the TLS handshake, most preferred first
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match self {
+            UpstreamProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            UpstreamProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            UpstreamProtocol::Http2 => vec![b"h2".to_vec()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::http_proxy::HttpProxy;
+    use crate::api::proxy::Proxy;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use crate::engine::rate_limiter::RateLimiter;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::State;
+    use axum::http::{Request, Response, StatusCode, Version};
+    use axum::routing::get;
+    use std::sync::Arc;
+
+    async fn proxy(State(proxy): State<Arc<HttpProxy>>, req: Request<Body>) -> Response<Body> {
+        match proxy.proxy_handler(req).await {
+            Ok(response) => response,
+            Err(_) => Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(Body::empty())
+                .unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_h2c_end_to_end_with_per_stream_limits() {
+        // answers with the version the proxy used
+        let upstream_url = spawn_upstream(Router::new().route(
+            "/version",
+            get(|req: Request<Body>| async move { format!("{:?}", req.version()) }),
+        ))
+        .await;
+        let upstream = Upstream::new("h2c", &upstream_url, RateLimiter::new(2, 60))
+            .with_protocol(UpstreamProtocol::Http2)
+            .unwrap();
+        let http_proxy = Arc::new(proxy_to(upstream));
+        let proxy_url = spawn_upstream(Router::new().fallback(proxy).with_state(http_proxy)).await;
+
+        // three streams multiplexed on a single prior knowledge connection
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let requests = (0..3).map(|_| {
+            client
+                .get(format!("{}/version", proxy_url))
+                .header("x-forwarded-for", "1.0.0.0")
+                .send()
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        let mut allowed = 0;
+        for res in responses {
+            let res = res.unwrap();
+            assert_eq!(res.version(), Version::HTTP_2);
+            if res.status() == StatusCode::OK {
+                assert_eq!(res.text().await.unwrap(), "HTTP/2.0");
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, 2);
+    }
+}
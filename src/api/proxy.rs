@@ -1,5 +1,7 @@
 use crate::api::model::{AuthorizationError, CallError};
+use axum::body::Body;
+use axum::http::Response;
 
 pub trait Proxy<T> {
-    async fn proxy_handler(&self, req: T) -> Result<(), CallError>;
+    async fn proxy_handler(&self, req: T) -> Result<Response<Body>, CallError>;
 }
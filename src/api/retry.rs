@@ -0,0 +1,147 @@
+use crate::api::model::Verb;
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub base_delay: Duration,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub max_delay: Duration,
+    pub budget_ratio: f64, // retries allowed per original request in the budget window
+    pub budget_min_retries: u32, // retries always allowed in the window, for low traffic
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub budget_window: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_secs(1),
+            budget_ratio: 0.2,
+            budget_min_retries: 10,
+            budget_window: Duration::from_secs(10),
+        }
+    }
+}
+
+struct BudgetWindow {
+    started: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+// Retries are limited to a fraction of the traffic so they cannot amplify an outage
+pub struct RetryPolicy {
+    pub config: RetryConfig,
+    budget: Arc<Mutex<BudgetWindow>>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig) -> RetryPolicy {
+        RetryPolicy {
+            config,
+            budget: Arc::new(Mutex::new(BudgetWindow {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            })),
+        }
+    }
+
+    // retries spent before a reload still count against the budget
+    pub fn carry_over(&mut self, previous: &RetryPolicy) {
+        self.budget = previous.budget.clone();
+    }
+
+    // a non idempotent request is only retried when the client sent an Idempotency-Key
+    pub fn is_retryable(verb: &Verb, has_idempotency_key: bool) -> bool {
+        match verb {
+            Verb::GET | Verb::PUT | Verb::DELETE => true,
+            Verb::POST | Verb::PATCH => has_idempotency_key,
+        }
+    }
+
+    pub fn record_request(&self) {
+        let mut budget = self.current_window();
+        budget.requests += 1;
+    }
+
+    // takes a retry out of the budget, false when it is exhausted
+    pub fn try_acquire_retry(&self) -> bool {
+        let mut budget = self.current_window();
+        let allowed = self.config.budget_min_retries as f64
+            + budget.requests as f64 * self.config.budget_ratio;
+        if (budget.retries as f64) < allowed {
+            budget.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // exponential backoff with full jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .config
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt));
+        let capped = exponential.min(self.config.max_delay);
+        capped.mul_f64(rand::rng().random::<f64>())
+    }
+
+    fn current_window(&self) -> std::sync::MutexGuard<'_, BudgetWindow> {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.started.elapsed() >= self.config.budget_window {
+            budget.started = Instant::now();
+            budget.requests = 0;
+            budget.retries = 0;
+        }
+        budget
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(RetryConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_budget() {
+        let policy = RetryPolicy::new(RetryConfig {
+            budget_ratio: 0.5,
+            budget_min_retries: 1,
+            ..RetryConfig::default()
+        });
+        for _ in 0..4 {
+            policy.record_request();
+        }
+        // 1 + 4 * 0.5
+        assert!(policy.try_acquire_retry());
+        assert!(policy.try_acquire_retry());
+        assert!(policy.try_acquire_retry());
+        assert!(!policy.try_acquire_retry());
+    }
+
+    #[test]
+    fn test_backoff_and_retryable_methods() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..10 {
+            assert!(policy.backoff(attempt) <= policy.config.max_delay);
+        }
+        assert!(RetryPolicy::is_retryable(&Verb::GET, false));
+        assert!(!RetryPolicy::is_retryable(&Verb::POST, false));
+        assert!(RetryPolicy::is_retryable(&Verb::POST, true));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::api::auth::Authenticator;
+use crate::api::cache::ResponseCache;
+use crate::api::circuit_breaker::CircuitBreaker;
+use crate::api::coalescing::RequestCoalescer;
+use crate::api::grpc::GrpcConfig;
+use crate::api::idempotency::IdempotencyStore;
+use crate::api::key_extractor::KeyExtractor;
+use crate::api::protocol::UpstreamProtocol;
+use crate::api::retry::RetryPolicy;
+use crate::api::rules::{LimitRule, RequestFacts, host_without_port};
+use crate::api::timeouts::Timeouts;
+use crate::api::upstream_pool::{LoadBalancing, UpstreamPool};
+use crate::api::upstream_tls::{UpstreamTlsConfig, UpstreamTlsError};
+use crate::api::websocket::WebSocketConfig;
+use crate::engine::rate_limiter::RateLimiter;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// A backend service the proxy forwards to, with its own limits
+pub struct Upstream {
+    pub name: String,
+    pub pool: UpstreamPool, // backend base urls, e.g. http://pets-service:8080
+    pub rate_limiter: RateLimiter,
+    pub key_extractor: KeyExtractor, // what the rate limiter counts requests by
+    pub rules: Vec<LimitRule>,       // the first matching rule replaces the limit above
+    pub authenticator: Option<Authenticator>, // None lets every request through
+    pub timeouts: Timeouts,
+    pub client: Option<Client>, // None uses the proxy client
+    pub protocol: UpstreamProtocol,
+    pub tls: Option<UpstreamTlsConfig>,
+    pub circuit_breaker: CircuitBreaker,
+    pub retry: RetryPolicy,
+    pub websocket: WebSocketConfig,
+    pub grpc: GrpcConfig,
+    pub cache: Option<ResponseCache>, // None disables caching
+    pub coalescing: Option<RequestCoalescer>,
+    pub idempotency: Option<IdempotencyStore>, // replays responses by Idempotency-Key
+}
+
+impl Upstream {
+    pub fn new(name: &str, url: &str, rate_limiter: RateLimiter) -> Upstream {
+        Upstream {
+            name: name.to_string(),
+            pool: UpstreamPool::new(&[url], LoadBalancing::RoundRobin),
+            rate_limiter,
+            key_extractor: KeyExtractor::default(),
+            rules: vec![],
+            authenticator: None,
+            timeouts: Timeouts::with_defaults(),
+            client: None,
+            protocol: UpstreamProtocol::default(),
+            tls: None,
+            circuit_breaker: CircuitBreaker::default(),
+            retry: RetryPolicy::default(),
+            websocket: WebSocketConfig::default(),
+            grpc: GrpcConfig::default(),
+            cache: None,
+            coalescing: None,
+            idempotency: None,
+        }
+    }
+
+    // gives the upstream its own client for a private CA, a client certificate or pins
+    pub fn with_tls(mut self, tls: &UpstreamTlsConfig) -> Result<Upstream, UpstreamTlsError> {
+        self.tls = Some(tls.clone());
+        self.build_client()?;
+        Ok(self)
+    }
+
+    pub fn with_protocol(
+        mut self,
+        protocol: UpstreamProtocol,
+    ) -> Result<Upstream, UpstreamTlsError> {
+        self.protocol = protocol;
+        self.build_client()?;
+        Ok(self)
+    }
+
+    // the limit and key of the first rule matching the request, or the upstream ones
+    pub fn limit_for(&self, request: &RequestFacts) -> (&RateLimiter, &KeyExtractor) {
+        self.rules
+            .iter()
+            .find(|rule| rule.condition.matches(request))
+            .map(|rule| (&rule.rate_limiter, &rule.key_extractor))
+            .unwrap_or((&self.rate_limiter, &self.key_extractor))
+    }
+
+    // keeps the state of the upstream it replaces: backend health, the circuit, the retry
+    // budget, stored responses, and the counters of the limits that did not change,
+    // counters are only comparable when requests are keyed the same way
+    pub fn carry_over(&mut self, previous: &Upstream) {
+        self.pool.carry_over(&previous.pool);
+        self.circuit_breaker.carry_over(&previous.circuit_breaker);
+        self.retry.carry_over(&previous.retry);
+        if let Some(cache) = self.cache.as_mut()
+            && let Some(previous) = &previous.cache
+        {
+            cache.carry_over(previous);
+        }
+        if let Some(idempotency) = self.idempotency.as_mut()
+            && let Some(previous) = &previous.idempotency
+        {
+            idempotency.carry_over(previous);
+        }
+        // rules are matched by their condition, whatever their order
+        for rule in self.rules.iter_mut() {
+            if let Some(previous) = previous.rules.iter().find(|previous| {
+                previous.when == rule.when && previous.key_extractor == rule.key_extractor
+            }) {
+                rule.rate_limiter.carry_over(&previous.rate_limiter);
+            }
+        }
+        if self.key_extractor != previous.key_extractor {
+            return;
+        }
+        self.rate_limiter.carry_over(&previous.rate_limiter);
+        if let Some(limiter) = self.websocket.message_limiter.as_mut()
+            && let Some(previous) = &previous.websocket.message_limiter
+        {
+            limiter.carry_over(previous);
+        }
+        for (method, limiter) in self.grpc.method_limiters.iter_mut() {
+            if let Some(previous) = previous.grpc.method_limiters.get(method) {
+                limiter.carry_over(previous);
+            }
+        }
+    }
+
+    // every limiter of the upstream, named so that the names survive a restart
+    pub fn limiters(&self) -> Vec<(String, &RateLimiter)> {
+        let mut limiters = vec![(self.name.clone(), &self.rate_limiter)];
+        if let Some(limiter) = &self.websocket.message_limiter {
+            limiters.push((format!("{} websocket", self.name), limiter));
+        }
+        for (method, limiter) in &self.grpc.method_limiters {
+            limiters.push((format!("{} grpc {}", self.name, method), limiter));
+        }
+        for rule in &self.rules {
+            limiters.push((
+                format!("{} rule {}", self.name, rule.when),
+                &rule.rate_limiter,
+            ));
+        }
+        limiters
+    }
+
+    fn build_client(&mut self) -> Result<(), UpstreamTlsError> {
+        let mut builder = self.protocol.configure(self.timeouts.client_builder());
+        if let Some(tls) = &self.tls {
+            builder = builder.use_preconfigured_tls(tls.client_config(self.protocol)?);
+            // WebSocket handshakes are HTTP/1.1 whatever the protocol of the upstream
+            let websocket = tls.client_config(UpstreamProtocol::Http1)?;
+            self.websocket.tls = Some(Arc::new(websocket));
+        }
+        self.client = Some(builder.build().map_err(UpstreamTlsError::Client)?);
+        Ok(())
+    }
+}
+
+pub struct Route {
+    pub host: Option<String>, // None matches any host
+    pub path_prefix: String,
+    pub strip_prefix: bool,
+    pub upstream: String,   // name of the upstream
+    pub timeouts: Timeouts, // overrides the upstream ones
+}
+
+impl Route {
+    pub fn new(host: Option<&str>, path_prefix: &str, upstream: &str) -> Route {
+        Route {
+            host: host.map(|h| h.to_ascii_lowercase()),
+            path_prefix: path_prefix.to_string(),
+            strip_prefix: false,
+            upstream: upstream.to_string(),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+        host_matches && path_has_prefix(path, &self.path_prefix)
+    }
+}
+
+// the prefix must end on a segment boundary: /pets matches /pets/1 but not /petshop
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub struct ResolvedRoute {
+    pub upstream: Arc<Upstream>,
+    pub forwarded_path: String, // path and query to append to the picked backend url
+    pub timeouts: Timeouts,
+}
+
+pub struct RoutingTable {
+    routes: Vec<Route>,
+    upstreams: HashMap<String, Arc<Upstream>>,
+}
+
+impl RoutingTable {
+    pub fn new(upstreams: Vec<Upstream>, routes: Vec<Route>) -> RoutingTable {
+        RoutingTable {
+            routes,
+            upstreams: upstreams
+                .into_iter()
+                .map(|u| (u.name.clone(), Arc::new(u)))
+                .collect(),
+        }
+    }
+
+    // one upstream receiving every request
+    #[cfg(test)]
+    pub fn single(url: &str, rate_limiter: RateLimiter) -> RoutingTable {
+        RoutingTable::new(
+            vec![Upstream::new("default", url, rate_limiter)],
+            vec![Route::new(None, "/", "default")],
+        )
+    }
+
+    // host specific routes win over catch-all ones, then the longest prefix wins
+    pub fn resolve(&self, host: Option<&str>, path_and_query: &str) -> Option<ResolvedRoute> {
+        let host = host.map(host_without_port);
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path_and_query, None),
+        };
+
+        let route = self
+            .routes
+            .iter()
+            .filter(|route| route.matches(host, path))
+            .max_by_key(|route| (route.host.is_some(), route.path_prefix.len()))?;
+        let upstream = self.upstreams.get(&route.upstream)?.clone();
+
+        let mut forwarded_path = path;
+        if route.strip_prefix {
+            forwarded_path = &path[route.path_prefix.trim_end_matches('/').len()..];
+        }
+        let mut forwarded_path = forwarded_path.to_string();
+        let query = match (query, &upstream.authenticator) {
+            (Some(query), Some(Authenticator::ApiKey(store))) => Some(store.strip_key(query)),
+            (query, _) => query.map(|query| query.to_string()),
+        };
+        if let Some(query) = query.filter(|query| !query.is_empty()) {
+            forwarded_path = format!("{}?{}", forwarded_path, query);
+        }
+
+        Some(ResolvedRoute {
+            timeouts: route.timeouts.or(&upstream.timeouts),
+            upstream,
+            forwarded_path,
+        })
+    }
+
+    // called on a table that is not shared yet, upstreams are matched by name
+    pub fn carry_over(&mut self, previous: &RoutingTable) {
+        // authenticators are shared by the upstreams, whatever their names
+        let authenticators: Vec<&Authenticator> = previous
+            .upstreams
+            .values()
+            .filter_map(|upstream| upstream.authenticator.as_ref())
+            .collect();
+        for (name, upstream) in self.upstreams.iter_mut() {
+            let Some(upstream) = Arc::get_mut(upstream) else {
+                continue;
+            };
+            if let Some(authenticator) = upstream.authenticator.as_mut() {
+                authenticator.carry_over(&authenticators);
+            }
+            if let Some(previous) = previous.upstreams.get(name) {
+                upstream.carry_over(previous);
+            }
+        }
+    }
+
+    pub fn limiters(&self) -> Vec<(String, &RateLimiter)> {
+        self.upstreams.values().flat_map(|u| u.limiters()).collect()
+    }
+
+    // one task per upstream with a health check, stopped once the table is dropped
+    pub fn spawn_health_checks(&self, client: &Client) {
+        for upstream in self.upstreams.values() {
+            let Some(health_check) = &upstream.pool.health_check else {
+                continue;
+            };
+            let interval = health_check.interval;
+            // the upstream client carries its TLS settings and connect timeout
+            let client = upstream.client.clone().unwrap_or_else(|| client.clone());
+            let upstream = Arc::downgrade(upstream);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let Some(upstream) = upstream.upgrade() else {
+                        break;
+                    };
+                    upstream.pool.check_health(&client).await;
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing_table() -> RoutingTable {
+        let mut strip = Route::new(None, "/legacy", "legacy");
+        strip.strip_prefix = true;
+        RoutingTable::new(
+            vec![
+                Upstream::new("default", "http://default:80/", RateLimiter::new(5, 60)),
+                Upstream::new("pets", "http://pets:8080", RateLimiter::new(5, 60)),
+                Upstream::new("admin", "http://admin:8080", RateLimiter::new(5, 60)),
+                Upstream::new("legacy", "http://legacy:8080", RateLimiter::new(5, 60)),
+            ],
+            vec![
+                Route::new(None, "/", "default"),
+                Route::new(None, "/pets", "pets"),
+                Route::new(Some("admin.example.com"), "/", "admin"),
+                Route::new(Some("[::1]"), "/", "admin"),
+                strip,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_resolve_route() {
+        let table = routing_table();
+
+        let route = table
+            .resolve(Some("api.example.com"), "/pets/1?x=1")
+            .unwrap();
+        assert_eq!(route.upstream.name, "pets");
+        assert_eq!(route.forwarded_path, "/pets/1?x=1");
+
+        let route = table.resolve(None, "/petshop").unwrap();
+        assert_eq!(route.upstream.name, "default");
+        assert_eq!(route.forwarded_path, "/petshop");
+
+        let route = table
+            .resolve(Some("Admin.example.com:443"), "/pets")
+            .unwrap();
+        assert_eq!(route.upstream.name, "admin");
+
+        let route = table.resolve(Some("[::1]:8080"), "/admin").unwrap();
+        assert_eq!(route.upstream.name, "admin");
+
+        let route = table.resolve(None, "/legacy/pets").unwrap();
+        assert_eq!(route.upstream.name, "legacy");
+        assert_eq!(route.forwarded_path, "/pets");
+    }
+
+    #[test]
+    fn test_resolve_no_route() {
+        let table = RoutingTable::new(vec![], vec![Route::new(None, "/pets", "missing")]);
+        assert!(table.resolve(None, "/pets").is_none());
+        assert!(table.resolve(None, "/users").is_none());
+    }
+}
@@ -0,0 +1,620 @@
+use crate::api::forwarding::{ClientInfo, ForwardingConfig, in_cidr, parse_cidr};
+use crate::api::key_extractor::KeyExtractor;
+use crate::engine::rate_limiter::RateLimiter;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, Request};
+use std::fmt;
+use std::net::IpAddr;
+
+// A condition on requests, e.g. header("x-plan") == "free" && path.starts_with("/search")
+//
+// operands: path, method, host, content_type, body_size, client_ip, header("name"), query("name")
+// tests: == and != on strings, <, <=, >, >=, == and != on body_size,
+//        .starts_with("..."), .ends_with("..."), .contains("...") on strings,
+//        .in_cidr("10.0.0.0/8") on client_ip
+// combined with &&, || and !, grouped with parentheses
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Test(Operand, Test),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Path,
+    Method,
+    Host,
+    ContentType, // the media type, without parameters
+    BodySize,    // from Content-Length, bodies are streamed and not measured
+    ClientIp,    // the peer address, or the X-Forwarded-For address behind trusted proxies
+    Header(HeaderName),
+    Query(String), // the raw value, not percent-decoded
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Test {
+    Equals(String),
+    NotEquals(String),
+    StartsWith(String),
+    EndsWith(String),
+    Contains(String),
+    InCidr(IpAddr, u8),
+    Size(Comparison, u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// Selects the limit and key of the requests matching its condition
+pub struct LimitRule {
+    pub when: String, // the condition as written, it names the limiter
+    pub condition: Condition,
+    pub rate_limiter: RateLimiter,
+    pub key_extractor: KeyExtractor,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RuleError {
+    pub column: usize, // 1-based, in the expression
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+// What conditions are evaluated against, borrowed from the request
+pub struct RequestFacts<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub headers: &'a HeaderMap,
+    pub host: Option<&'a str>,
+    pub client_ip: Option<IpAddr>,
+}
+
+impl<'a> RequestFacts<'a> {
+    pub fn new<T>(
+        req: &'a Request<T>,
+        client: &'a ClientInfo,
+        forwarding: &ForwardingConfig,
+    ) -> RequestFacts<'a> {
+        RequestFacts {
+            method: req.method().as_str(),
+            path: req.uri().path(),
+            query: req.uri().query(),
+            headers: req.headers(),
+            host: client.host.as_deref(),
+            client_ip: forwarding.client_ip(req.headers(), client),
+        }
+    }
+
+    fn value(&self, operand: &Operand) -> Option<String> {
+        let header = |name: &HeaderName| {
+            let value = self.headers.get(name)?;
+            Some(String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+        match operand {
+            Operand::Path => Some(self.path.to_string()),
+            Operand::Method => Some(self.method.to_string()),
+            Operand::Host => self.host.map(|host| host_without_port(host).to_string()),
+            Operand::ContentType => header(&CONTENT_TYPE).map(|value| {
+                value
+                    .split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_ascii_lowercase()
+            }),
+            Operand::BodySize => header(&CONTENT_LENGTH),
+            Operand::ClientIp => self.client_ip.map(|ip| ip.to_string()),
+            Operand::Header(name) => header(name),
+            Operand::Query(name) => self.query.and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| match pair.split_once('=') {
+                        Some((key, value)) if key == name => Some(value.to_string()),
+                        None if pair == name => Some(String::new()),
+                        _ => None,
+                    })
+            }),
+        }
+    }
+}
+
+// The name of a Host header, IPv6 addresses keep their brackets: [::1]:8080 is [::1]
+pub fn host_without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit())
+                && (name.ends_with(']') || !name.contains(':')) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, RuleError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            end: source.len() + 1,
+        };
+        let condition = parser.or()?;
+        match parser.peek() {
+            None => Ok(condition),
+            Some((column, token)) => Err(RuleError {
+                column: *column,
+                message: format!("unexpected {}", token),
+            }),
+        }
+    }
+
+    pub fn matches(&self, request: &RequestFacts) -> bool {
+        match self {
+            Condition::And(left, right) => left.matches(request) && right.matches(request),
+            Condition::Or(left, right) => left.matches(request) || right.matches(request),
+            Condition::Not(condition) => !condition.matches(request),
+            Condition::Test(operand, test) => {
+                let Some(value) = request.value(operand) else {
+                    // a missing header is different from any value
+                    return matches!(test, Test::NotEquals(_));
+                };
+                let case_insensitive = matches!(operand, Operand::Method | Operand::Host);
+                match test {
+                    Test::Equals(expected) if case_insensitive => {
+                        value.eq_ignore_ascii_case(expected)
+                    }
+                    Test::NotEquals(expected) if case_insensitive => {
+                        !value.eq_ignore_ascii_case(expected)
+                    }
+                    Test::Equals(expected) => value == *expected,
+                    Test::NotEquals(expected) => value != *expected,
+                    Test::StartsWith(prefix) => value.starts_with(prefix.as_str()),
+                    Test::EndsWith(suffix) => value.ends_with(suffix.as_str()),
+                    Test::Contains(part) => value.contains(part.as_str()),
+                    Test::InCidr(network, prefix) => value
+                        .parse::<IpAddr>()
+                        .is_ok_and(|ip| in_cidr(ip, *network, *prefix)),
+                    Test::Size(comparison, size) => value
+                        .parse::<u64>()
+                        .is_ok_and(|value| comparison.holds(value, *size)),
+                }
+            }
+        }
+    }
+}
+
+impl Comparison {
+    fn holds(&self, value: u64, expected: u64) -> bool {
+        match self {
+            Comparison::Equal => value == expected,
+            Comparison::NotEqual => value != expected,
+            Comparison::Less => value < expected,
+            Comparison::LessOrEqual => value <= expected,
+            Comparison::Greater => value > expected,
+            Comparison::GreaterOrEqual => value >= expected,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(u64),
+    Dot,
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Compare(Comparison),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "`{}`", ident),
+            Token::Str(value) => write!(f, "\"{}\"", value),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Dot => write!(f, "`.`"),
+            Token::LeftParen => write!(f, "`(`"),
+            Token::RightParen => write!(f, "`)`"),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Not => write!(f, "`!`"),
+            Token::Compare(_) => write!(f, "comparison"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, RuleError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        let column = offset + 1;
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+        let error = |message: &str| RuleError {
+            column,
+            message: message.to_string(),
+        };
+        let (token, length) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('.', _) => (Token::Dot, 1),
+            ('(', _) => (Token::LeftParen, 1),
+            (')', _) => (Token::RightParen, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Compare(Comparison::Equal), 2),
+            ('!', Some('=')) => (Token::Compare(Comparison::NotEqual), 2),
+            ('<', Some('=')) => (Token::Compare(Comparison::LessOrEqual), 2),
+            ('>', Some('=')) => (Token::Compare(Comparison::GreaterOrEqual), 2),
+            ('<', _) => (Token::Compare(Comparison::Less), 1),
+            ('>', _) => (Token::Compare(Comparison::Greater), 1),
+            ('!', _) => (Token::Not, 1),
+            ('"', _) => {
+                let mut value = String::new();
+                let mut end = i + 1;
+                loop {
+                    match chars.get(end).map(|(_, c)| *c) {
+                        None => return Err(error("unterminated string")),
+                        Some('"') => break,
+                        Some('\\') if end + 1 < chars.len() => {
+                            value.push(chars[end + 1].1);
+                            end += 2;
+                        }
+                        Some(c) => {
+                            value.push(c);
+                            end += 1;
+                        }
+                    }
+                }
+                (Token::Str(value), end + 1 - i)
+            }
+            (c, _) if c.is_ascii_digit() => {
+                let length = chars[i..]
+                    .iter()
+                    .take_while(|(_, c)| c.is_ascii_digit())
+                    .count();
+                let digits: String = chars[i..i + length].iter().map(|(_, c)| c).collect();
+                let number = digits.parse().map_err(|_| error("number too large"))?;
+                (Token::Number(number), length)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let length = chars[i..]
+                    .iter()
+                    .take_while(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                    .count();
+                let ident: String = chars[i..i + length].iter().map(|(_, c)| c).collect();
+                (Token::Ident(ident), length)
+            }
+            (c, _) => return Err(error(&format!("unexpected character `{}`", c))),
+        };
+        tokens.push((column, token));
+        i += length;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize, // column reported for errors at the end of the expression
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(usize, Token)> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<(usize, Token), RuleError> {
+        let token = self.tokens.get(self.position).cloned().ok_or(RuleError {
+            column: self.end,
+            message: format!("expected {}", expected),
+        })?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek().is_some_and(|(_, next)| next == token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), RuleError> {
+        let (column, found) = self.next(&token.to_string())?;
+        if found == token {
+            Ok(())
+        } else {
+            Err(RuleError {
+                column,
+                message: format!("expected {}, found {}", token, found),
+            })
+        }
+    }
+
+    fn string(&mut self) -> Result<(usize, String), RuleError> {
+        match self.next("a string")? {
+            (column, Token::Str(value)) => Ok((column, value)),
+            (column, found) => Err(RuleError {
+                column,
+                message: format!("expected a string, found {}", found),
+            }),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, RuleError> {
+        let mut condition = self.and()?;
+        while self.eat(&Token::Or) {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, RuleError> {
+        let mut condition = self.unary()?;
+        while self.eat(&Token::And) {
+            condition = Condition::And(Box::new(condition), Box::new(self.unary()?));
+        }
+        Ok(condition)
+    }
+
+    fn unary(&mut self) -> Result<Condition, RuleError> {
+        if self.eat(&Token::Not) {
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::LeftParen) {
+            let condition = self.or()?;
+            self.expect(Token::RightParen)?;
+            return Ok(condition);
+        }
+        let (column, operand) = self.operand()?;
+        let test = self.test(&operand)?;
+        let invalid = match (&operand, &test) {
+            (Operand::BodySize, Test::Size(..)) => false,
+            (Operand::BodySize, _) | (_, Test::Size(..)) => true,
+            (Operand::ClientIp, Test::StartsWith(_) | Test::EndsWith(_) | Test::Contains(_)) => {
+                true
+            }
+            (Operand::ClientIp, _) | (_, Test::InCidr(..)) => !matches!(operand, Operand::ClientIp),
+            _ => false,
+        };
+        if invalid {
+            return Err(RuleError {
+                column,
+                message: "this test does not apply to this operand".to_string(),
+            });
+        }
+        Ok(Condition::Test(operand, test))
+    }
+
+    fn operand(&mut self) -> Result<(usize, Operand), RuleError> {
+        let (column, token) = self.next("an operand")?;
+        let Token::Ident(name) = token else {
+            return Err(RuleError {
+                column,
+                message: format!("expected an operand, found {}", token),
+            });
+        };
+        let operand = match name.as_str() {
+            "path" => Operand::Path,
+            "method" => Operand::Method,
+            "host" => Operand::Host,
+            "content_type" => Operand::ContentType,
+            "body_size" => Operand::BodySize,
+            "client_ip" => Operand::ClientIp,
+            "header" | "query" => {
+                self.expect(Token::LeftParen)?;
+                let (name_column, argument) = self.string()?;
+                self.expect(Token::RightParen)?;
+                if name == "query" {
+                    Operand::Query(argument)
+                } else {
+                    let header =
+                        HeaderName::from_bytes(argument.as_bytes()).map_err(|_| RuleError {
+                            column: name_column,
+                            message: format!("invalid header name \"{}\"", argument),
+                        })?;
+                    Operand::Header(header)
+                }
+            }
+            _ => {
+                return Err(RuleError {
+                    column,
+                    message: format!("unknown operand `{}`", name),
+                });
+            }
+        };
+        Ok((column, operand))
+    }
+
+    fn test(&mut self, operand: &Operand) -> Result<Test, RuleError> {
+        let (column, token) = self.next("a comparison or a method")?;
+        match token {
+            Token::Compare(comparison) if *operand == Operand::BodySize => {
+                match self.next("a number")? {
+                    (_, Token::Number(size)) => Ok(Test::Size(comparison, size)),
+                    (column, found) => Err(RuleError {
+                        column,
+                        message: format!("expected a number, found {}", found),
+                    }),
+                }
+            }
+            Token::Compare(Comparison::Equal) => Ok(Test::Equals(self.string()?.1)),
+            Token::Compare(Comparison::NotEqual) => Ok(Test::NotEquals(self.string()?.1)),
+            Token::Compare(_) => Err(RuleError {
+                column,
+                message: "only body_size can be ordered".to_string(),
+            }),
+            Token::Dot => {
+                let (column, method) = self.next("a method")?;
+                self.expect(Token::LeftParen)?;
+                let (argument_column, argument) = self.string()?;
+                self.expect(Token::RightParen)?;
+                match method {
+                    Token::Ident(method) if method == "starts_with" => {
+                        Ok(Test::StartsWith(argument))
+                    }
+                    Token::Ident(method) if method == "ends_with" => Ok(Test::EndsWith(argument)),
+                    Token::Ident(method) if method == "contains" => Ok(Test::Contains(argument)),
+                    Token::Ident(method) if method == "in_cidr" => match parse_cidr(&argument) {
+                        Some((network, prefix)) => Ok(Test::InCidr(network, prefix)),
+                        None => Err(RuleError {
+                            column: argument_column,
+                            message: format!("invalid CIDR \"{}\"", argument),
+                        }),
+                    },
+                    method => Err(RuleError {
+                        column,
+                        message: format!("unknown method {}", method),
+                    }),
+                }
+            }
+            found => Err(RuleError {
+                column,
+                message: format!("expected a comparison or a method, found {}", found),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(condition: &str, req: Request<()>) -> bool {
+        let client = ClientInfo {
+            ip: Some("192.168.1.10".parse().unwrap()),
+            ..ClientInfo::default()
+        };
+        let facts = RequestFacts::new(&req, &client, &ForwardingConfig::default());
+        Condition::parse(condition).unwrap().matches(&facts)
+    }
+
+    #[test]
+    fn test_evaluate_conditions() {
+        let free_search = || {
+            Request::post("/search/pets?q=rex&debug")
+                .header("x-plan", "free")
+                .header("content-type", "application/json; charset=utf-8")
+                .header("content-length", "2048")
+                .body(())
+                .unwrap()
+        };
+        let condition = r#"header("x-plan") == "free" && path.starts_with("/search")"#;
+        assert!(matches(condition, free_search()));
+        assert!(!matches(
+            condition,
+            Request::get("/search").body(()).unwrap()
+        ));
+
+        assert!(matches(
+            r#"method == "post" && content_type == "application/json""#,
+            free_search()
+        ));
+        assert!(matches(
+            r#"body_size > 1024 && !(body_size >= 4096)"#,
+            free_search()
+        ));
+        assert!(matches(
+            r#"query("q") == "rex" && query("debug") == """#,
+            free_search()
+        ));
+        assert!(matches(r#"header("x-tier") != "gold""#, free_search()));
+        assert!(matches(
+            r#"client_ip.in_cidr("192.168.0.0/16")"#,
+            free_search()
+        ));
+        assert!(!matches(
+            r#"client_ip.in_cidr("10.0.0.0/8") || path == "/""#,
+            free_search()
+        ));
+    }
+
+    #[test]
+    fn test_host_without_port() {
+        assert_eq!(host_without_port("example.com:8080"), "example.com");
+        assert_eq!(host_without_port("example.com"), "example.com");
+        assert_eq!(host_without_port("[::1]:8080"), "[::1]");
+        assert_eq!(host_without_port("[::1]"), "[::1]");
+        assert_eq!(host_without_port("[2001:db8::7]"), "[2001:db8::7]");
+    }
+
+    #[test]
+    fn test_forwarded_for_only_from_trusted_proxies() {
+        let spoofed = Request::get("/")
+            .header("x-forwarded-for", "10.0.0.1, 172.16.0.9")
+            .body(())
+            .unwrap();
+        let client_ip = |peer: &str, trusted_proxies: &[&str]| {
+            let client = ClientInfo {
+                ip: Some(peer.parse().unwrap()),
+                ..ClientInfo::default()
+            };
+            let forwarding = ForwardingConfig {
+                trusted_proxies: trusted_proxies
+                    .iter()
+                    .map(|cidr| parse_cidr(cidr).unwrap())
+                    .collect(),
+                ..ForwardingConfig::default()
+            };
+            let facts = RequestFacts::new(&spoofed, &client, &forwarding);
+            facts.client_ip.unwrap().to_string()
+        };
+        assert_eq!(client_ip("192.168.1.10", &[]), "192.168.1.10");
+        assert_eq!(client_ip("192.168.1.10", &["10.0.0.0/8"]), "192.168.1.10");
+        assert_eq!(client_ip("192.168.1.10", &["192.168.0.0/16"]), "172.16.0.9");
+        assert_eq!(
+            client_ip("192.168.1.10", &["192.168.0.0/16", "172.16.0.0/12"]),
+            "10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |condition: &str| Condition::parse(condition).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"header("x-plan") = "free""#),
+            "unexpected character `=` at column 18"
+        );
+        assert_eq!(
+            error(r#"path.starts_with("/search""#),
+            "expected `)` at column 27"
+        );
+        assert_eq!(
+            error(r#"plan == "free""#),
+            "unknown operand `plan` at column 1"
+        );
+        assert_eq!(
+            error(r#"path > 10"#),
+            "only body_size can be ordered at column 6"
+        );
+        assert_eq!(
+            error(r#"client_ip.in_cidr("10.0.0.0/33")"#),
+            "invalid CIDR \"10.0.0.0/33\" at column 19"
+        );
+    }
+}
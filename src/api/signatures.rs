@@ -0,0 +1,236 @@
+use crate::api::auth::Identity;
+use crate::api::model::AuthorizationError;
+use axum::http::Request;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// The SHA-256 of a buffered request body, hex encoded, set as a request extension
+// before signatures are checked
+#[derive(Clone, Debug)]
+pub struct BodyDigest(pub String);
+
+impl BodyDigest {
+    pub fn of(body: &[u8]) -> BodyDigest {
+        BodyDigest(hex::encode(Sha256::digest(body)))
+    }
+}
+
+// What the signature covers, one line each in the configured order
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignedComponent {
+    Method,
+    Path,
+    Query, // empty without a query string
+    Timestamp,
+    Nonce,
+    BodySha256,
+    Header(String), // empty when the header is missing
+}
+
+// The shared secret of a partner, by key id
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartnerSecret {
+    pub secret: String,
+    pub plan: Option<String>,
+}
+
+// Checks HMAC-SHA256 signatures, hex encoded, over the canonical form of the request
+#[derive(Clone)]
+pub struct HmacVerifier {
+    pub secrets: HashMap<String, PartnerSecret>,
+    pub key_id_header: String,
+    pub signature_header: String,
+    pub timestamp_header: String, // unix seconds
+    pub nonce_header: String,
+    pub components: Vec<SignedComponent>,
+    pub clock_skew: Duration,          // accepted in both directions
+    pub max_body_size: usize,          // signed bodies are held in memory to be hashed
+    nonces: Arc<DashMap<String, i64>>, // seen nonces by key id, until their timestamp is too old
+    inserts: Arc<AtomicUsize>,
+}
+
+const PURGE_EVERY: usize = 1000;
+
+impl HmacVerifier {
+    pub fn new(secrets: HashMap<String, PartnerSecret>) -> HmacVerifier {
+        HmacVerifier {
+            secrets,
+            key_id_header: "x-key-id".to_string(),
+            signature_header: "x-signature".to_string(),
+            timestamp_header: "x-timestamp".to_string(),
+            nonce_header: "x-nonce".to_string(),
+            components: vec![
+                SignedComponent::Method,
+                SignedComponent::Path,
+                SignedComponent::Timestamp,
+                SignedComponent::Nonce,
+                SignedComponent::BodySha256,
+            ],
+            clock_skew: Duration::from_secs(300),
+            max_body_size: 1024 * 1024,
+            nonces: Arc::new(DashMap::new()),
+            inserts: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // the nonces seen before a reload stay used, requests signed before it cannot be replayed
+    pub fn carry_over(&mut self, previous: &HmacVerifier) {
+        self.nonces = previous.nonces.clone();
+        self.inserts = previous.inserts.clone();
+    }
+
+    // the body digest must have been set, requests that were not buffered are refused
+    pub fn authenticate<T>(&self, req: &Request<T>) -> Result<Identity, AuthorizationError> {
+        self.verify(req, chrono::Utc::now().timestamp())
+            .map_err(|err| {
+                println!("Invalid signature: {}", err);
+                AuthorizationError::Unauthenticated
+            })
+    }
+
+    fn verify<T>(&self, req: &Request<T>, now: i64) -> Result<Identity, String> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+        let key_id = header(&self.key_id_header).ok_or("missing key id")?;
+        let partner = self.secrets.get(&key_id).ok_or("unknown key id")?;
+        let signature = header(&self.signature_header).ok_or("missing signature")?;
+        let signature = hex::decode(signature.trim()).map_err(|_| "signature is not hex")?;
+        let timestamp = header(&self.timestamp_header).ok_or("missing timestamp")?;
+        let timestamp: i64 = timestamp.trim().parse().map_err(|_| "invalid timestamp")?;
+        let skew = self.clock_skew.as_secs() as i64;
+        if now.abs_diff(timestamp) > skew as u64 {
+            return Err("timestamp outside of the clock skew window".to_string());
+        }
+        let nonce = header(&self.nonce_header);
+        let digest = req
+            .extensions()
+            .get::<BodyDigest>()
+            .ok_or("body was not read")?;
+
+        let lines: Vec<String> = self
+            .components
+            .iter()
+            .map(|component| match component {
+                SignedComponent::Method => req.method().as_str().to_string(),
+                SignedComponent::Path => req.uri().path().to_string(),
+                SignedComponent::Query => req.uri().query().unwrap_or("").to_string(),
+                SignedComponent::Timestamp => timestamp.to_string(),
+                SignedComponent::Nonce => nonce.clone().unwrap_or_default(),
+                SignedComponent::BodySha256 => digest.0.clone(),
+                SignedComponent::Header(name) => header(name).unwrap_or_default(),
+            })
+            .collect();
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(partner.secret.as_bytes()).map_err(|e| e.to_string())?;
+        mac.update(lines.join("\n").as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature mismatch")?;
+
+        // only checked once the signature is valid, so forged requests cannot burn nonces
+        if self.components.contains(&SignedComponent::Nonce) {
+            let nonce = nonce
+                .filter(|nonce| !nonce.is_empty())
+                .ok_or("missing nonce")?;
+            self.remember(format!("{} {}", key_id, nonce), timestamp + skew, now)?;
+        }
+        Ok(Identity {
+            id: key_id,
+            plan: partner.plan.clone(),
+        })
+    }
+
+    fn remember(&self, nonce: String, expires: i64, now: i64) -> Result<(), String> {
+        if self
+            .inserts
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(PURGE_EVERY)
+        {
+            self.nonces.retain(|_, expires| *expires >= now);
+        }
+        match self.nonces.entry(nonce) {
+            dashmap::Entry::Occupied(seen) if *seen.get() >= now => {
+                Err("nonce already used".to_string())
+            }
+            entry => {
+                entry.insert(expires);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_signatures() {
+        let partner = PartnerSecret {
+            secret: "partner-secret".to_string(),
+            plan: None,
+        };
+        let verifier = HmacVerifier::new(HashMap::from([("partner-a".to_string(), partner)]));
+        let now = 1_700_000_000;
+        let body = br#"{"name":"rex"}"#;
+        let signed = |timestamp: i64, nonce: &str, secret: &str| {
+            let canonical = format!(
+                "POST\n/pets\n{}\n{}\n{}",
+                timestamp,
+                nonce,
+                BodyDigest::of(body).0
+            );
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(canonical.as_bytes());
+            let mut req = Request::post("/pets?debug=1")
+                .header("x-key-id", "partner-a")
+                .header("x-timestamp", timestamp.to_string())
+                .header("x-nonce", nonce)
+                .header("x-signature", hex::encode(mac.finalize().into_bytes()))
+                .body(())
+                .unwrap();
+            req.extensions_mut().insert(BodyDigest::of(body));
+            req
+        };
+
+        let identity = verifier
+            .verify(&signed(now - 10, "n-1", "partner-secret"), now)
+            .unwrap();
+        assert_eq!(identity.id, "partner-a");
+        let replayed = verifier.verify(&signed(now - 10, "n-1", "partner-secret"), now);
+        assert_eq!(replayed.unwrap_err(), "nonce already used");
+        let forged = verifier.verify(&signed(now, "n-2", "guessed-secret"), now);
+        assert_eq!(forged.unwrap_err(), "signature mismatch");
+        let stale = verifier.verify(&signed(now - 600, "n-3", "partner-secret"), now);
+        assert_eq!(
+            stale.unwrap_err(),
+            "timestamp outside of the clock skew window"
+        );
+
+        let overflowing = verifier.verify(&signed(i64::MIN, "n-5", "partner-secret"), now);
+        assert!(overflowing.is_err());
+
+        // a reloaded verifier still knows the nonces
+        let mut reloaded = HmacVerifier::new(verifier.secrets.clone());
+        reloaded.carry_over(&verifier);
+        let replayed = reloaded.verify(&signed(now - 10, "n-1", "partner-secret"), now);
+        assert_eq!(replayed.unwrap_err(), "nonce already used");
+
+        let mut tampered = signed(now, "n-4", "partner-secret");
+        tampered.extensions_mut().insert(BodyDigest::of(b"{}"));
+        assert_eq!(
+            verifier.verify(&tampered, now).unwrap_err(),
+            "signature mismatch"
+        );
+    }
+}
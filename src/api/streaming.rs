@@ -0,0 +1,236 @@
+use crate::api::timeouts::Timeouts;
+use crate::api::upstream_pool::BackendGuard;
+use axum::body::{Body, Bytes};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderName, Response, StatusCode};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::io;
+use std::time::{Duration, Instant};
+
+// headers only meaningful for a single connection, never relayed
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream"))
+}
+
+// A response read in full, to be stored or sent more than once
+#[derive(Clone, Debug)]
+pub struct BufferedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl BufferedResponse {
+    // reads the body up to the limit, a larger one is handed back to be streamed with the
+    // chunks already read, None when the body could not be read
+    pub async fn read(
+        response: Response<Body>,
+        limit: usize,
+    ) -> Result<BufferedResponse, Option<Response<Body>>> {
+        let (parts, body) = response.into_parts();
+        let mut stream = body.into_data_stream();
+        let mut chunks: Vec<Bytes> = vec![];
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|err| {
+                println!("Downstream error: {}", err);
+                None
+            })?;
+            size += chunk.len();
+            chunks.push(chunk);
+            if size > limit {
+                let read = stream::iter(chunks.into_iter().map(Ok));
+                let body = Body::from_stream(read.chain(stream));
+                return Err(Some(Response::from_parts(parts, body)));
+            }
+        }
+        Ok(BufferedResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: chunks.concat().into(),
+        })
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+struct RelayState {
+    upstream: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    done: bool,
+    _backend: BackendGuard, // the backend slot stays taken while the body is streamed
+}
+
+// Relays the upstream body chunk by chunk instead of buffering it.
+// Event streams only get the idle timeout, other bodies also get the total one.
+pub fn into_response(
+    res: reqwest::Response,
+    timeouts: &Timeouts,
+    started: Instant,
+    backend: BackendGuard,
+) -> Response<Body> {
+    let mut response = Response::builder().status(res.status());
+    let headers = response
+        .headers_mut()
+        .expect("Oups! building response failed");
+    for (name, value) in res.headers() {
+        if !is_hop_by_hop(name) {
+            headers.append(name, value.clone());
+        }
+    }
+
+    let deadline = match is_event_stream(res.headers()) {
+        true => None,
+        false => timeouts.total.map(|total| started + total),
+    };
+    let state = RelayState {
+        upstream: res.bytes_stream().boxed(),
+        deadline,
+        idle: timeouts.idle,
+        done: false,
+        _backend: backend,
+    };
+    let body = stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        let next = match state.wait() {
+            Some(wait) => tokio::time::timeout(wait, state.upstream.next()).await,
+            None => Ok(state.upstream.next().await),
+        };
+        let chunk = match next {
+            Ok(None) => return None,
+            Ok(Some(Ok(bytes))) => Ok(bytes),
+            Ok(Some(Err(err))) => Err(io::Error::other(err)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upstream body timed out",
+            )),
+        };
+        state.done = chunk.is_err();
+        Some((chunk, state))
+    });
+
+    response
+        .body(Body::from_stream(body))
+        .expect("Oups! building response failed")
+}
+
+impl RelayState {
+    // how long to wait for the next chunk, the closest of the idle and total limits
+    fn wait(&self) -> Option<Duration> {
+        let until_deadline = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (self.idle, until_deadline) {
+            (Some(idle), Some(until_deadline)) => Some(idle.min(until_deadline)),
+            (idle, until_deadline) => idle.or(until_deadline),
+        }
+    }
+}
+
+pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::http_proxy::HttpProxy;
+    use crate::api::proxy::Proxy;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use crate::engine::rate_limiter::RateLimiter;
+    use axum::Router;
+    use axum::body::{Body, Bytes};
+    use axum::http::{Request, Response};
+    use axum::routing::get;
+    use futures::{StreamExt, stream};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    // sends each chunk after the given delay
+    fn slow_body(content_type: &'static str, chunks: Vec<(u64, &'static str)>) -> Response<Body> {
+        let body = stream::iter(chunks).then(|(delay, chunk)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok::<Bytes, Infallible>(Bytes::from(chunk))
+        });
+        Response::builder()
+            .header("content-type", content_type)
+            .body(Body::from_stream(body))
+            .unwrap()
+    }
+
+    async fn proxy(total: u64, idle: u64) -> HttpProxy {
+        let app = Router::new()
+            .route(
+                "/events",
+                get(|| async {
+                    slow_body(
+                        "text/event-stream",
+                        vec![(0, "data: 1\n\n"), (300, "data: 2\n\n")],
+                    )
+                }),
+            )
+            .route(
+                "/stalled",
+                get(|| async { slow_body("application/json", vec![(0, "{"), (2000, "}")]) }),
+            );
+        let mut upstream = Upstream::new(
+            "streams",
+            &spawn_upstream(app).await,
+            RateLimiter::new(10, 60),
+        );
+        upstream.timeouts.total = Some(Duration::from_millis(total));
+        upstream.timeouts.idle = Some(Duration::from_millis(idle));
+        proxy_to(upstream)
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::get(path)
+            .header("x-forwarded-for", "1.0.0.0")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_outlives_total_timeout() {
+        let proxy = proxy(100, 1000).await;
+        let res = proxy.proxy_handler(request("/events")).await.ok().unwrap();
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "data: 1\n\ndata: 2\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_cuts_stalled_body() {
+        let proxy = proxy(5000, 100).await;
+        let res = proxy.proxy_handler(request("/stalled")).await.ok().unwrap();
+        let mut body = res.into_body().into_data_stream();
+
+        assert_eq!(body.next().await.unwrap().unwrap(), "{");
+        assert!(body.next().await.unwrap().is_err());
+    }
+}
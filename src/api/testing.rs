@@ -0,0 +1,29 @@
+use crate::api::forwarding::ForwardingConfig;
+use crate::api::http_proxy::HttpProxy;
+use crate::api::routing::{Route, RoutingTable, Upstream};
+use axum::Router;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+// Fixtures shared by the tests that run requests through the proxy
+
+// serves the app on a local port, returns its base url
+pub async fn spawn_upstream(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+// a proxy sending every request to the upstream
+pub fn proxy_to(upstream: Upstream) -> HttpProxy {
+    let name = upstream.name.clone();
+    HttpProxy {
+        routing: Arc::new(RoutingTable::new(
+            vec![upstream],
+            vec![Route::new(None, "/", &name)],
+        )),
+        client: reqwest::Client::new(),
+        forwarding: ForwardingConfig::default(),
+    }
+}
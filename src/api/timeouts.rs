@@ -0,0 +1,68 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_TOTAL_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// None means "not set": a route inherits the value of its upstream
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    #[serde(deserialize_with = "crate::config::de::option_duration")]
+    pub connect: Option<Duration>, // client level, only honored per upstream
+    #[serde(deserialize_with = "crate::config::de::option_duration")]
+    pub first_byte: Option<Duration>, // until the response headers are received
+    #[serde(deserialize_with = "crate::config::de::option_duration")]
+    pub total: Option<Duration>, // not applied to the body of event streams
+    #[serde(deserialize_with = "crate::config::de::option_duration")]
+    pub idle: Option<Duration>, // between two chunks of a streamed body
+}
+
+impl Timeouts {
+    pub fn with_defaults() -> Timeouts {
+        Timeouts {
+            connect: Some(DEFAULT_CONNECT_TIMEOUT),
+            first_byte: Some(DEFAULT_FIRST_BYTE_TIMEOUT),
+            total: Some(DEFAULT_TOTAL_TIMEOUT),
+            idle: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    // values set on self win over the fallback ones
+    pub fn or(&self, fallback: &Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(fallback.connect),
+            first_byte: self.first_byte.or(fallback.first_byte),
+            total: self.total.or(fallback.total),
+            idle: self.idle.or(fallback.idle),
+        }
+    }
+
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect) = self.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_timeouts_override_upstream() {
+        let route = Timeouts {
+            first_byte: Some(Duration::from_secs(1)),
+            ..Timeouts::default()
+        };
+        let merged = route.or(&Timeouts::with_defaults());
+
+        assert_eq!(merged.connect, Some(DEFAULT_CONNECT_TIMEOUT));
+        assert_eq!(merged.first_byte, Some(Duration::from_secs(1)));
+        assert_eq!(merged.total, Some(DEFAULT_TOTAL_TIMEOUT));
+    }
+}
@@ -0,0 +1,269 @@
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+const VIRTUAL_NODES_PER_BACKEND: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash, // on the client key, so a client sticks to the same backend
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    pub path: String,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub interval: Duration,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub timeout: Duration,
+    pub unhealthy_threshold: u32, // consecutive failures before removing a backend
+    pub healthy_threshold: u32,   // consecutive successes before adding it back
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            path: "/health".to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+pub struct Backend {
+    pub url: String,
+    healthy: AtomicBool,
+    outstanding: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    consecutive_successes: AtomicU32,
+}
+
+impl Backend {
+    fn new(url: &str) -> Backend {
+        Backend {
+            url: url.trim_end_matches('/').to_string(),
+            healthy: AtomicBool::new(true),
+            outstanding: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            consecutive_successes: AtomicU32::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    fn record_check(&self, success: bool, health_check: &HealthCheck) {
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            let successes = self.consecutive_successes.fetch_add(1, Ordering::Relaxed) + 1;
+            if !self.is_healthy() && successes >= health_check.healthy_threshold {
+                println!("Backend {} is healthy again", self.url);
+                self.healthy.store(true, Ordering::Relaxed);
+            }
+        } else {
+            self.consecutive_successes.store(0, Ordering::Relaxed);
+            let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.is_healthy() && failures >= health_check.unhealthy_threshold {
+                println!("Backend {} removed from rotation", self.url);
+                self.healthy.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Counts the request as outstanding on its backend until dropped
+pub struct BackendGuard {
+    pub backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    fn new(backend: Arc<Backend>) -> BackendGuard {
+        backend.outstanding.fetch_add(1, Ordering::Relaxed);
+        BackendGuard { backend }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamPool {
+    backends: Vec<Arc<Backend>>,
+    strategy: LoadBalancing,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>, // (hash, backend index), sorted by hash
+    pub health_check: Option<HealthCheck>,
+}
+
+impl UpstreamPool {
+    pub fn new(urls: &[&str], strategy: LoadBalancing) -> UpstreamPool {
+        let backends: Vec<Arc<Backend>> =
+            urls.iter().map(|url| Arc::new(Backend::new(url))).collect();
+        let mut ring: Vec<(u64, usize)> = vec![];
+        for (index, backend) in backends.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES_PER_BACKEND {
+                ring.push((hash(&format!("{}#{}", backend.url, node)), index));
+            }
+        }
+        ring.sort();
+
+        UpstreamPool {
+            backends,
+            strategy,
+            next: AtomicUsize::new(0),
+            ring,
+            health_check: None,
+        }
+    }
+
+    // backends kept by the new configuration keep their health and outstanding requests
+    pub fn carry_over(&mut self, previous: &UpstreamPool) {
+        for backend in self.backends.iter_mut() {
+            if let Some(kept) = previous.backends.iter().find(|b| b.url == backend.url) {
+                *backend = kept.clone();
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    // None when every backend is out of rotation
+    pub fn pick(&self, client_key: &str) -> Option<BackendGuard> {
+        let backend = match self.strategy {
+            LoadBalancing::RoundRobin => self.pick_round_robin(),
+            LoadBalancing::LeastOutstanding => self
+                .backends
+                .iter()
+                .filter(|b| b.is_healthy())
+                .min_by_key(|b| b.outstanding()),
+            LoadBalancing::ConsistentHash => self.pick_consistent_hash(client_key),
+        }?;
+        Some(BackendGuard::new(backend.clone()))
+    }
+
+    fn pick_round_robin(&self) -> Option<&Arc<Backend>> {
+        let len = self.backends.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| &self.backends[(start + offset) % len])
+            .find(|b| b.is_healthy())
+    }
+
+    // walk the ring clockwise from the key, skipping unhealthy backends
+    fn pick_consistent_hash(&self, client_key: &str) -> Option<&Arc<Backend>> {
+        let key_hash = hash(client_key);
+        let start = self.ring.partition_point(|(h, _)| *h < key_hash);
+        (0..self.ring.len())
+            .map(|offset| &self.backends[self.ring[(start + offset) % self.ring.len()].1])
+            .find(|b| b.is_healthy())
+    }
+
+    pub async fn check_health(&self, client: &Client) {
+        let Some(health_check) = &self.health_check else {
+            return;
+        };
+        for backend in &self.backends {
+            let res = client
+                .get(format!("{}{}", backend.url, health_check.path))
+                .timeout(health_check.timeout)
+                .send()
+                .await;
+            let success = match res {
+                Ok(res) => res.status().is_success() || res.status().is_redirection(),
+                Err(_) => false,
+            };
+            backend.record_check(success, health_check);
+        }
+    }
+}
+
+fn hash(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::testing::spawn_upstream;
+    use axum::Router;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+
+    #[test]
+    fn test_round_robin_skips_unhealthy() {
+        let pool = UpstreamPool::new(
+            &["http://a", "http://b", "http://c"],
+            LoadBalancing::RoundRobin,
+        );
+        pool.backends()[1].healthy.store(false, Ordering::Relaxed);
+
+        let picked: Vec<String> = (0..4)
+            .map(|_| pool.pick("").unwrap().backend.url.clone())
+            .collect();
+        assert_eq!(picked, vec!["http://a", "http://c", "http://c", "http://a"]);
+    }
+
+    #[test]
+    fn test_least_outstanding_and_consistent_hash() {
+        let pool = UpstreamPool::new(&["http://a", "http://b"], LoadBalancing::LeastOutstanding);
+        let first = pool.pick("").unwrap();
+        let second = pool.pick("").unwrap();
+        assert_ne!(first.backend.url, second.backend.url);
+        drop(first);
+        assert_eq!(
+            pool.backends()[0].outstanding() + pool.backends()[1].outstanding(),
+            1
+        );
+
+        let pool = UpstreamPool::new(
+            &["http://a", "http://b", "http://c"],
+            LoadBalancing::ConsistentHash,
+        );
+        let url = pool.pick("1.0.0.0").unwrap().backend.url.clone();
+        for _ in 0..5 {
+            assert_eq!(pool.pick("1.0.0.0").unwrap().backend.url, url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_removes_failing_backend() {
+        let app = Router::new().route(
+            "/health",
+            get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+        );
+        let url = spawn_upstream(app).await;
+
+        let mut pool = UpstreamPool::new(&[&url], LoadBalancing::RoundRobin);
+        pool.health_check = Some(HealthCheck::default());
+        let client = Client::new();
+        for _ in 0..3 {
+            pool.check_health(&client).await;
+        }
+
+        assert!(!pool.backends()[0].is_healthy());
+        assert!(pool.pick("").is_none());
+    }
+}
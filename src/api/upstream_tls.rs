@@ -0,0 +1,301 @@
+use crate::api::protocol::UpstreamProtocol;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+// How the proxy connects to an upstream over TLS, every field is optional
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    pub ca_bundle: Option<PathBuf>, // PEM, replaces the system roots when set
+    pub client_cert: Option<PathBuf>, // PEM, presented for mutual TLS
+    pub client_key: Option<PathBuf>,
+    pub pinned_sha256: Vec<String>, // hex SHA-256 of the accepted leaf certificates
+}
+
+#[derive(Debug)]
+pub enum UpstreamTlsError {
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    InvalidCertificate(PathBuf, rustls::Error),
+    MissingClientKey,
+    InvalidPin(String),
+    Verifier(String),
+    Client(reqwest::Error),
+}
+
+impl fmt::Display for UpstreamTlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pem(path, e) => write!(f, "Failed to read {}: {:?}", path.display(), e),
+            Self::InvalidCertificate(path, e) => {
+                write!(f, "Invalid certificate {}: {}", path.display(), e)
+            }
+            Self::MissingClientKey => write!(f, "client_cert and client_key go together"),
+            Self::InvalidPin(pin) => write!(f, "Invalid SHA-256 pin {}", pin),
+            Self::Verifier(e) => write!(f, "Failed to build certificate verifier: {}", e),
+            Self::Client(e) => write!(f, "Failed to build http client: {}", e),
+        }
+    }
+}
+
+impl UpstreamTlsConfig {
+    pub fn client_config(
+        &self,
+        protocol: UpstreamProtocol,
+    ) -> Result<ClientConfig, UpstreamTlsError> {
+        let provider = Arc::new(default_provider());
+        let roots = Arc::new(self.root_store()?);
+        let mut verifier: Arc<dyn ServerCertVerifier> =
+            WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+                .build()
+                .map_err(|e| UpstreamTlsError::Verifier(e.to_string()))?;
+        if !self.pinned_sha256.is_empty() {
+            verifier = Arc::new(PinnedVerifier {
+                inner: verifier,
+                pins: self.pins()?,
+            });
+        }
+
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| UpstreamTlsError::Verifier(e.to_string()))?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut config = match (&self.client_cert, &self.client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = load_certificates(cert_path)?;
+                let key = PrivateKeyDer::from_pem_file(key_path)
+                    .map_err(|e| UpstreamTlsError::Pem(key_path.clone(), e))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| UpstreamTlsError::InvalidCertificate(cert_path.clone(), e))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(UpstreamTlsError::MissingClientKey),
+        };
+        config.alpn_protocols = protocol.alpn_protocols();
+        Ok(config)
+    }
+
+    fn root_store(&self) -> Result<RootCertStore, UpstreamTlsError> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle {
+            Some(ca_bundle) => {
+                for cert in load_certificates(ca_bundle)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| UpstreamTlsError::InvalidCertificate(ca_bundle.clone(), e))?;
+                }
+            }
+            None => {
+                let (added, ignored) =
+                    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+                if ignored > 0 {
+                    println!(
+                        "Ignored {} system root certificates, {} loaded",
+                        ignored, added
+                    );
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn pins(&self) -> Result<Vec<Vec<u8>>, UpstreamTlsError> {
+        self.pinned_sha256
+            .iter()
+            .map(|pin| {
+                let cleaned = pin.replace(':', "");
+                match hex::decode(&cleaned) {
+                    Ok(bytes) if bytes.len() == 32 => Ok(bytes),
+                    _ => Err(UpstreamTlsError::InvalidPin(pin.clone())),
+                }
+            })
+            .collect()
+    }
+}
+
+fn load_certificates(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, UpstreamTlsError> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| UpstreamTlsError::Pem(path.clone(), e))
+}
+
+// The usual chain validation, then the leaf certificate must match one of the pins
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let fingerprint = Sha256::digest(end_entity.as_ref());
+        if self
+            .pins
+            .iter()
+            .any(|pin| pin.as_slice() == fingerprint.as_slice())
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "upstream certificate does not match the pinned fingerprints".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::proxy::Proxy;
+    use crate::api::routing::Upstream;
+    use crate::api::testing::proxy_to;
+    use crate::engine::rate_limiter::RateLimiter;
+    use crate::server::tls::{CertificateConfig, SniResolver, TlsListener};
+    use axum::Router;
+    use axum::routing::get;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+
+    const RESOURCES: &str = "src/resources/tls";
+
+    fn pem(name: &str) -> PathBuf {
+        format!("{}/{}", RESOURCES, name).into()
+    }
+
+    // an https upstream for localhost which requires a client certificate
+    // issued for api.example.com
+    async fn spawn_mtls_upstream() -> u16 {
+        let resolver = SniResolver::load(vec![CertificateConfig {
+            hostnames: vec!["localhost".to_string()],
+            cert_path: pem("localhost.cert.pem"),
+            key_path: pem("localhost.key.pem"),
+        }])
+        .unwrap();
+        let mut client_roots = RootCertStore::empty();
+        client_roots
+            .add(CertificateDer::from_pem_file(pem("api.example.com.cert.pem")).unwrap())
+            .unwrap();
+        let client_verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(client_roots),
+            Arc::new(default_provider()),
+        )
+        .build()
+        .unwrap();
+        let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_cert_resolver(Arc::new(resolver));
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = tcp_listener.local_addr().unwrap().port();
+        let listener =
+            TlsListener::new(tcp_listener, TlsAcceptor::from(Arc::new(server_config))).unwrap();
+        let app = Router::new().route("/", get(|| async { "internal" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        port
+    }
+
+    fn fingerprint(name: &str) -> String {
+        let cert = CertificateDer::from_pem_file(pem(name)).unwrap();
+        hex::encode(Sha256::digest(cert.as_ref()))
+    }
+
+    fn upstream_client(url: &str, config: &UpstreamTlsConfig) -> reqwest::Client {
+        let upstream = Upstream::new("internal", url, RateLimiter::new(10, 60));
+        upstream.with_tls(config).unwrap().client.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_with_private_ca_and_pin() {
+        let port = spawn_mtls_upstream().await;
+        let url = format!("https://localhost:{}/", port);
+        let config = UpstreamTlsConfig {
+            ca_bundle: Some(pem("localhost.cert.pem")),
+            client_cert: Some(pem("api.example.com.cert.pem")),
+            client_key: Some(pem("api.example.com.key.pem")),
+            pinned_sha256: vec![fingerprint("localhost.cert.pem")],
+        };
+        let upstream = Upstream::new("internal", &url, RateLimiter::new(10, 60))
+            .with_tls(&config)
+            .unwrap();
+        let proxy = proxy_to(upstream);
+        let req = axum::http::Request::get("/")
+            .header("x-forwarded-for", "1.0.0.0")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = proxy.proxy_handler(req).await.ok().unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+        assert_eq!(body.unwrap(), "internal");
+
+        // the handshake fails without a client certificate
+        let without_identity = UpstreamTlsConfig {
+            client_cert: None,
+            client_key: None,
+            ..config.clone()
+        };
+        let client = upstream_client(&url, &without_identity);
+        let res = client.get(&url).send().await;
+        assert!(res.is_err() || !res.unwrap().status().is_success());
+
+        // or when the upstream certificate does not match the pin
+        let wrong_pin = UpstreamTlsConfig {
+            pinned_sha256: vec![fingerprint("api.example.com.cert.pem")],
+            ..config
+        };
+        let client = upstream_client(&url, &wrong_pin);
+        assert!(client.get(&url).send().await.is_err());
+    }
+}
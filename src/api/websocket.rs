@@ -0,0 +1,220 @@
+use crate::api::routing::Upstream;
+use crate::api::upstream_pool::BackendGuard;
+use crate::engine::rate_limiter::RateLimiter;
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::http::header::{SEC_WEBSOCKET_PROTOCOL, UPGRADE};
+use axum::http::{HeaderMap, HeaderValue, Request};
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame as UpstreamCloseFrame;
+use tokio_tungstenite::tungstenite::{self, Message as UpstreamMessage};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+const POLICY_VIOLATION: u16 = 1008;
+
+pub type UpstreamSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Default)]
+pub struct WebSocketConfig {
+    // limits the text/binary messages a client sends for the lifetime of its connections
+    pub message_limiter: Option<RateLimiter>,
+    // the TLS settings of the upstream, None uses the system roots
+    pub tls: Option<Arc<ClientConfig>>,
+}
+
+pub fn is_upgrade_request<T>(req: &Request<T>) -> bool {
+    req.headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+pub fn to_websocket_url(http_url: &str) -> String {
+    if let Some(rest) = http_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = http_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        http_url.to_string()
+    }
+}
+
+// opens the upstream connection, returns it with the sub-protocol the upstream selected
+pub async fn connect(
+    url: &str,
+    headers: HeaderMap,
+    client_headers: &HeaderMap,
+    upstream: &Upstream,
+) -> Result<(UpstreamSocket, Option<HeaderValue>), tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().extend(headers);
+    if let Some(protocols) = client_headers.get(SEC_WEBSOCKET_PROTOCOL) {
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocols.clone());
+    }
+    let connector = upstream.websocket.tls.clone().map(Connector::Rustls);
+    let connecting =
+        tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector);
+    let (socket, response) = match upstream.timeouts.connect {
+        Some(connect) => tokio::time::timeout(connect, connecting)
+            .await
+            .map_err(|_| tungstenite::Error::Io(io::ErrorKind::TimedOut.into()))??,
+        None => connecting.await?,
+    };
+    let protocol = response.headers().get(SEC_WEBSOCKET_PROTOCOL).cloned();
+    Ok((socket, protocol))
+}
+
+// relays messages both ways until one side closes
+pub async fn tunnel(
+    client: WebSocket,
+    upstream_socket: UpstreamSocket,
+    upstream: Arc<Upstream>,
+    rate_limit_key: String,
+    _backend: BackendGuard,
+) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream_socket.split();
+    let limiter = upstream.websocket.message_limiter.as_ref();
+
+    let client_to_upstream = async {
+        while let Some(Ok(message)) = client_rx.next().await {
+            let is_data = matches!(message, Message::Text(_) | Message::Binary(_));
+            if is_data
+                && let Some(limiter) = limiter
+                && !limiter.is_authorized(&rate_limit_key)
+            {
+                return true;
+            }
+            if upstream_tx
+                .send(to_upstream_message(message))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        false
+    };
+    let upstream_to_client = async {
+        while let Some(Ok(message)) = upstream_rx.next().await {
+            let Some(message) = to_client_message(message) else {
+                continue;
+            };
+            if client_tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let rate_limited = tokio::select! {
+        rate_limited = client_to_upstream => rate_limited,
+        _ = upstream_to_client => false,
+    };
+    if rate_limited {
+        println!(
+            "WebSocket message rate limit exceeded for {}",
+            rate_limit_key
+        );
+        let _ = client_tx
+            .send(Message::Close(Some(CloseFrame {
+                code: POLICY_VIOLATION,
+                reason: "Too Many Messages".into(),
+            })))
+            .await;
+    }
+    let _ = upstream_tx.send(UpstreamMessage::Close(None)).await;
+}
+
+fn to_upstream_message(message: Message) -> UpstreamMessage {
+    match message {
+        Message::Text(text) => UpstreamMessage::Text(text.as_str().into()),
+        Message::Binary(data) => UpstreamMessage::Binary(data),
+        Message::Ping(data) => UpstreamMessage::Ping(data),
+        Message::Pong(data) => UpstreamMessage::Pong(data),
+        Message::Close(frame) => UpstreamMessage::Close(frame.map(|frame| UpstreamCloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason.as_str().into(),
+        })),
+    }
+}
+
+fn to_client_message(message: UpstreamMessage) -> Option<Message> {
+    match message {
+        UpstreamMessage::Text(text) => Some(Message::Text(text.as_str().into())),
+        UpstreamMessage::Binary(data) => Some(Message::Binary(data)),
+        UpstreamMessage::Ping(data) => Some(Message::Ping(data)),
+        UpstreamMessage::Pong(data) => Some(Message::Pong(data)),
+        UpstreamMessage::Close(frame) => Some(Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason.as_str().into(),
+        }))),
+        UpstreamMessage::Frame(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::key_extractor::KeyExtractor;
+    use crate::api::testing::{proxy_to, spawn_upstream};
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::WebSocketUpgrade;
+    use axum::routing::any;
+
+    #[tokio::test]
+    async fn test_tunnel_with_message_rate_limit() {
+        let echo = Router::new().route(
+            "/ws",
+            any(|ws: WebSocketUpgrade| async {
+                ws.on_upgrade(|mut socket: WebSocket| async move {
+                    while let Some(Ok(message)) = socket.recv().await {
+                        if socket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            }),
+        );
+        let mut upstream =
+            Upstream::new("echo", &spawn_upstream(echo).await, RateLimiter::new(5, 60));
+        upstream.websocket.message_limiter = Some(RateLimiter::new(2, 60));
+        upstream.key_extractor = KeyExtractor::Header("x-api-key".to_string());
+        let proxy = Arc::new(proxy_to(upstream));
+        let app = Router::new().fallback(move |req: Request<Body>| async move {
+            proxy.websocket_handler(req).await.ok().unwrap()
+        });
+        let proxy_url = spawn_upstream(app).await;
+        let connect = |forwarded_for: &'static str| {
+            let mut request = format!("{}/ws", proxy_url.replacen("http", "ws", 1))
+                .into_client_request()
+                .unwrap();
+            let headers = request.headers_mut();
+            headers.insert("x-api-key", HeaderValue::from_static("key-1"));
+            headers.insert("x-forwarded-for", HeaderValue::from_static(forwarded_for));
+            tokio_tungstenite::connect_async(request)
+        };
+
+        // messages are counted by the key of the handshake, not the forwarded address
+        let (mut first, _) = connect("1.0.0.0").await.unwrap();
+        let (mut socket, _) = connect("2.0.0.0").await.unwrap();
+        for (socket, text) in [(&mut first, "one"), (&mut socket, "two")] {
+            socket.send(UpstreamMessage::text(text)).await.unwrap();
+            let echoed = socket.next().await.unwrap().unwrap();
+            assert_eq!(echoed.to_text().unwrap(), text);
+        }
+        socket.send(UpstreamMessage::text("three")).await.unwrap();
+        match socket.next().await.unwrap().unwrap() {
+            UpstreamMessage::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), POLICY_VIOLATION)
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+}
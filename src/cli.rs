@@ -0,0 +1,151 @@
+use crate::config::loader::{ConfigError, Segment};
+use crate::config::model::Config;
+use crate::engine::body_analyzer::{BodyAnalyzer, OPEN_API_FILE};
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "rate-limiter",
+    version,
+    about = "A rate limiting reverse proxy"
+)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub serve: ConfigArgs, // without a subcommand the proxy is served
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the proxy
+    Serve(ConfigArgs),
+    /// Parse and check a configuration without binding any port
+    ValidateConfig(ConfigArgs),
+    /// Report the routes and schemas of the OpenAPI spec
+    CheckOpenapi {
+        #[arg(long, env = "RATE_LIMITER_OPENAPI", default_value = OPEN_API_FILE)]
+        spec: PathBuf,
+    },
+}
+
+// flags win over environment variables, which win over the configuration file
+#[derive(Clone, Debug, Args)]
+pub struct ConfigArgs {
+    /// Path of the YAML configuration
+    #[arg(short, long, env = "RATE_LIMITER_CONFIG", default_value = "config.yml")]
+    pub config: PathBuf,
+    /// Overrides listeners.http.address
+    #[arg(long, env = "RATE_LIMITER_HTTP_ADDRESS")]
+    pub http_address: Option<SocketAddr>,
+    /// Overrides listeners.https.address
+    #[arg(long, env = "RATE_LIMITER_HTTPS_ADDRESS")]
+    pub https_address: Option<SocketAddr>,
+}
+
+impl ConfigArgs {
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::load(&self.config)?;
+        if let Some(address) = self.http_address {
+            config.listeners.http.address = address;
+        }
+        if let Some(address) = self.https_address {
+            let Some(https) = config.listeners.https.as_mut() else {
+                let path: Vec<Segment> = vec!["listeners".into(), "https".into()];
+                return Err(config.invalid(&path, "an https address is set but no https listener"));
+            };
+            https.address = address;
+        }
+        Ok(config)
+    }
+}
+
+// everything serve does before binding its listeners
+pub fn validate_config(args: &ConfigArgs) -> Result<(), ConfigError> {
+    let config = args.load()?;
+    let runtime = config.build()?;
+    println!("{} is valid", args.config.display());
+    println!("  http listener: {}", runtime.http);
+    if let Some((address, tls_config)) = &runtime.https {
+        let certificates = tls_config.certificates.len();
+        println!(
+            "  https listener: {}, {} certificate(s)",
+            address, certificates
+        );
+    }
+    if let Some(plans) = &config.plans {
+        let tiers: Vec<&str> = plans.tiers.keys().map(|tier| tier.as_str()).collect();
+        println!(
+            "  plans: {}, keys from {}",
+            tiers.join(", "),
+            plans.keys_file.display()
+        );
+    }
+    for upstream in &config.upstreams {
+        let limit = &config.limits[&upstream.limit];
+        println!(
+            "  upstream {}: {} backends, {} requests per {}s",
+            upstream.name,
+            upstream.urls.len(),
+            limit.rate,
+            limit.window.as_secs()
+        );
+        for rule in &upstream.rules {
+            println!("    when {}: limit {}", rule.when, rule.limit);
+        }
+    }
+    for route in &config.routes {
+        let host = route.host.as_deref().unwrap_or("*");
+        println!(
+            "  route {}{} -> {}",
+            host, route.path_prefix, route.upstream
+        );
+    }
+    Ok(())
+}
+
+pub fn check_openapi(spec: &Path) -> Result<(), String> {
+    let report =
+        BodyAnalyzer::describe_open_api(spec).map_err(|e| format!("{}: {}", spec.display(), e))?;
+    println!("{} {} ({})", report.title, report.version, spec.display());
+    for route in &report.routes {
+        println!("  {} {}", route.verbs.join(", "), route.path);
+        for (verb, content_type, schema) in &route.request_bodies {
+            println!("    {} body {}: {}", verb, content_type, schema);
+        }
+    }
+    println!("  schemas: {}", report.schemas.join(", "));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_arguments() {
+        let cli = Cli::try_parse_from(["rate-limiter"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.serve.config, PathBuf::from("config.yml"));
+
+        let cli = Cli::try_parse_from([
+            "rate-limiter",
+            "validate-config",
+            "--config",
+            "prod.yml",
+            "--http-address",
+            "127.0.0.1:8080",
+        ])
+        .unwrap();
+        let Some(Command::ValidateConfig(args)) = cli.command else {
+            panic!("expected validate-config");
+        };
+        assert_eq!(args.config, PathBuf::from("prod.yml"));
+        assert_eq!(args.http_address, Some("127.0.0.1:8080".parse().unwrap()));
+
+        assert!(Cli::try_parse_from(["rate-limiter", "serve", "--http-address", "x"]).is_err());
+    }
+}
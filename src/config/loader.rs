@@ -0,0 +1,825 @@
+use crate::api::api_keys::{ApiKey, ApiKeyStore};
+use crate::api::auth::AuthMethod;
+use crate::api::introspection::Introspector;
+use crate::api::jwt::JwtValidator;
+use crate::api::key_extractor::KeyExtractor;
+use crate::api::rules::Condition;
+use crate::api::signatures::{HmacVerifier, PartnerSecret};
+use crate::api::timeouts::Timeouts;
+use crate::config::model::{Config, LimitConfig, PlanKeyConfig};
+use crate::engine::plans::{Plans, Quota};
+use crate::engine::rate_limiter::RateLimiter;
+use axum::http::HeaderName;
+use jsonwebtoken::jwk::JwkSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(serde_yaml::Error), // carries the line and column
+    Invalid {
+        path: String, // e.g. upstreams[1].limit
+        line: Option<usize>,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Parse(e) => write!(f, "Invalid configuration: {}", e),
+            Self::Invalid {
+                path,
+                line: Some(line),
+                message,
+            } => write!(
+                f,
+                "Invalid configuration at line {} ({}): {}",
+                line, path, message
+            ),
+            Self::Invalid {
+                path,
+                line: None,
+                message,
+            } => write!(f, "Invalid configuration ({}): {}", path, message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl From<&str> for Segment {
+    fn from(key: &str) -> Segment {
+        Segment::Key(key.to_string())
+    }
+}
+
+impl From<usize> for Segment {
+    fn from(index: usize) -> Segment {
+        Segment::Index(index)
+    }
+}
+
+// longer durations are refused, they overflow the clocks they are added to
+const MAX_DURATION: Duration = Duration::from_secs(3650 * 24 * 60 * 60);
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let source = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Config::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Config, ConfigError> {
+        let mut config: Config = serde_yaml::from_str(source).map_err(ConfigError::Parse)?;
+        config.source = source.to_string();
+        config.validate()?;
+        Ok(config)
+    }
+
+    // references and values serde cannot check on its own
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, limit) in &self.limits {
+            self.check_quota(&["limits".into(), name.as_str().into()], limit)?;
+        }
+        if let Some(plans) = &self.plans {
+            for (name, limit) in &plans.tiers {
+                self.check_quota(
+                    &["plans".into(), "tiers".into(), name.as_str().into()],
+                    limit,
+                )?;
+            }
+            if let Some(plan) = &plans.default
+                && !plans.tiers.contains_key(plan)
+            {
+                let path: Vec<Segment> = vec!["plans".into(), "default".into()];
+                return Err(self.invalid(&path, format!("unknown plan `{}`", plan)));
+            }
+        }
+
+        if let Some(limit) = self
+            .auth
+            .introspection
+            .as_ref()
+            .and_then(|c| c.limit.as_ref())
+        {
+            let path: Vec<Segment> = vec!["auth".into(), "introspection".into(), "limit".into()];
+            self.check_limit(&path, limit)?;
+        }
+
+        let mut names = HashSet::new();
+        for (i, upstream) in self.upstreams.iter().enumerate() {
+            let at = |key: &str| -> Vec<Segment> { vec!["upstreams".into(), i.into(), key.into()] };
+            if !names.insert(upstream.name.as_str()) {
+                return Err(self.invalid(
+                    &at("name"),
+                    format!("upstream `{}` is defined twice", upstream.name),
+                ));
+            }
+            if upstream.urls.is_empty() {
+                return Err(self.invalid(&at("urls"), "at least one url is required"));
+            }
+            for (j, url) in upstream.urls.iter().enumerate() {
+                let valid = reqwest::Url::parse(url)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+                if !valid {
+                    let mut path = at("urls");
+                    path.push(j.into());
+                    return Err(self.invalid(&path, format!("`{}` is not an http(s) url", url)));
+                }
+            }
+            self.check_limit(&at("limit"), &upstream.limit)?;
+            self.check_key(&at("key"), &upstream.key)?;
+            self.check_timeouts(&at("timeouts"), &upstream.timeouts)?;
+            let within = |section: &str, key: &str| -> Vec<Segment> {
+                vec!["upstreams".into(), i.into(), section.into(), key.into()]
+            };
+            if let Some(health_check) = &upstream.health_check {
+                self.check_interval(&within("health_check", "interval"), health_check.interval)?;
+                self.check_interval(&within("health_check", "timeout"), health_check.timeout)?;
+            }
+            let (retry, breaker) = (&upstream.retry, &upstream.circuit_breaker);
+            let durations = [
+                (within("retry", "base_delay"), retry.base_delay),
+                (within("retry", "max_delay"), retry.max_delay),
+                (within("retry", "budget_window"), retry.budget_window),
+                (
+                    within("circuit_breaker", "slow_call_threshold"),
+                    breaker.slow_call_threshold,
+                ),
+                (
+                    within("circuit_breaker", "open_duration"),
+                    breaker.open_duration,
+                ),
+            ];
+            for (path, duration) in durations {
+                self.check_duration(&path, duration)?;
+            }
+            if let Some(idempotency) = &upstream.idempotency {
+                self.check_duration(&within("idempotency", "ttl"), idempotency.ttl)?;
+            }
+            if upstream.plans && self.plans.is_none() {
+                return Err(self.invalid(&at("plans"), "no plans are configured"));
+            }
+            let configured = match upstream.auth {
+                Some(AuthMethod::ApiKey) => self.auth.api_keys.is_some(),
+                Some(AuthMethod::Jwt) => self.auth.jwt.is_some(),
+                Some(AuthMethod::Hmac) => self.auth.hmac.is_some(),
+                Some(AuthMethod::Introspection) => self.auth.introspection.is_some(),
+                None => true,
+            };
+            if !configured {
+                let message = format!("auth.{} is not configured", auth_name(upstream.auth));
+                return Err(self.invalid(&at("auth"), message));
+            }
+            if let Some(limit) = &upstream.websocket.message_limit {
+                let mut path = at("websocket");
+                path.push("message_limit".into());
+                self.check_limit(&path, limit)?;
+            }
+            for (method, limit) in &upstream.grpc.method_limits {
+                let mut path = at("grpc");
+                path.extend(["method_limits".into(), method.as_str().into()]);
+                self.check_limit(&path, limit)?;
+            }
+            for (j, rule) in upstream.rules.iter().enumerate() {
+                let at = |key: &str| -> Vec<Segment> {
+                    vec![
+                        "upstreams".into(),
+                        i.into(),
+                        "rules".into(),
+                        j.into(),
+                        key.into(),
+                    ]
+                };
+                if let Err(err) = Condition::parse(&rule.when) {
+                    return Err(self.invalid(&at("when"), err.to_string()));
+                }
+                self.check_limit(&at("limit"), &rule.limit)?;
+                if let Some(key) = &rule.key {
+                    self.check_key(&at("key"), key)?;
+                }
+            }
+        }
+
+        for (i, route) in self.routes.iter().enumerate() {
+            let at = |key: &str| -> Vec<Segment> { vec!["routes".into(), i.into(), key.into()] };
+            if !route.path_prefix.starts_with('/') {
+                return Err(self.invalid(&at("path_prefix"), "must start with /"));
+            }
+            self.check_timeouts(&at("timeouts"), &route.timeouts)?;
+            if !names.contains(route.upstream.as_str()) {
+                return Err(self.invalid(
+                    &at("upstream"),
+                    format!("unknown upstream `{}`", route.upstream),
+                ));
+            }
+        }
+
+        if let Some(path) = &self.shutdown.readiness_path
+            && !path.starts_with('/')
+        {
+            return Err(self.invalid(
+                &["shutdown".into(), "readiness_path".into()],
+                "must start with /",
+            ));
+        }
+
+        if let Some(https) = &self.listeners.https {
+            if https.certificates.is_empty() {
+                return Err(self.invalid(
+                    &["listeners".into(), "https".into(), "certificates".into()],
+                    "at least one certificate is required",
+                ));
+            }
+            self.check_interval(
+                &["listeners".into(), "https".into(), "reload_interval".into()],
+                https.reload_interval,
+            )?;
+        }
+        self.check_interval(&["reload".into(), "interval".into()], self.reload.interval)?;
+        self.check_duration(
+            &["shutdown".into(), "grace_period".into()],
+            self.shutdown.grace_period,
+        )?;
+        self.check_auth_durations()
+    }
+
+    fn check_auth_durations(&self) -> Result<(), ConfigError> {
+        let at = |section: &str, key: &str| -> Vec<Segment> {
+            vec!["auth".into(), section.into(), key.into()]
+        };
+        if let Some(jwt) = &self.auth.jwt {
+            self.check_duration(&at("jwt", "leeway"), jwt.leeway)?;
+        }
+        if let Some(clock_skew) = self.auth.hmac.as_ref().and_then(|hmac| hmac.clock_skew) {
+            self.check_duration(&at("hmac", "clock_skew"), clock_skew)?;
+        }
+        if let Some(introspection) = &self.auth.introspection {
+            let ttls = [
+                ("active_ttl", introspection.active_ttl),
+                ("inactive_ttl", introspection.inactive_ttl),
+            ];
+            for (key, ttl) in ttls {
+                if let Some(ttl) = ttl {
+                    self.check_duration(&at("introspection", key), ttl)?;
+                }
+            }
+            if let Some(timeout) = introspection.timeout {
+                self.check_interval(&at("introspection", "timeout"), timeout)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the plan of every key of the keys file, with its overrides applied
+    pub fn load_plans(&self) -> Result<Option<Plans>, ConfigError> {
+        let Some(config) = &self.plans else {
+            return Ok(None);
+        };
+        let invalid = |message: String| {
+            let path: Vec<Segment> = vec!["plans".into(), "keys_file".into()];
+            self.invalid(
+                &path,
+                format!("{}: {}", config.keys_file.display(), message),
+            )
+        };
+        let tiers: HashMap<String, Quota> = config
+            .tiers
+            .iter()
+            .map(|(name, tier)| {
+                let quota = Quota {
+                    rate: tier.rate,
+                    window: tier.window.as_secs(),
+                };
+                (name.clone(), quota)
+            })
+            .collect();
+        let source =
+            std::fs::read_to_string(&config.keys_file).map_err(|e| invalid(e.to_string()))?;
+        let keys: BTreeMap<String, PlanKeyConfig> =
+            serde_yaml::from_str(&source).map_err(|e| invalid(e.to_string()))?;
+
+        let mut quotas = HashMap::new();
+        for (key, entry) in keys {
+            let plan = *tiers
+                .get(&entry.plan)
+                .ok_or_else(|| invalid(format!("unknown plan `{}` for `{}`", entry.plan, key)))?;
+            if let Some(window) = entry.window
+                && (window.subsec_nanos() != 0 || window > MAX_DURATION)
+            {
+                return Err(invalid(format!(
+                    "the window of `{}` must be a whole number of seconds, at most 3650 days",
+                    key
+                )));
+            }
+            let window = entry.window.map_or(plan.window, |window| window.as_secs());
+            if window == 0 {
+                return Err(invalid(format!(
+                    "the window of `{}` must be at least 1s",
+                    key
+                )));
+            }
+            let rate = entry.rate.unwrap_or(plan.rate);
+            quotas.insert(key, Quota { rate, window });
+        }
+        let default = config
+            .default
+            .as_ref()
+            .and_then(|plan| tiers.get(plan))
+            .copied();
+        Ok(Some(Plans {
+            tiers,
+            quotas,
+            default,
+        }))
+    }
+
+    // active and revoked keys, by the hash of the key
+    pub fn load_api_keys(&self) -> Result<Option<ApiKeyStore>, ConfigError> {
+        let Some(config) = &self.auth.api_keys else {
+            return Ok(None);
+        };
+        let invalid = |message: String| {
+            let path: Vec<Segment> = vec!["auth".into(), "api_keys".into(), "store_file".into()];
+            self.invalid(
+                &path,
+                format!("{}: {}", config.store_file.display(), message),
+            )
+        };
+        let source =
+            std::fs::read_to_string(&config.store_file).map_err(|e| invalid(e.to_string()))?;
+        let keys: HashMap<String, ApiKey> =
+            serde_yaml::from_str(&source).map_err(|e| invalid(e.to_string()))?;
+        let tiers = self.plans.as_ref().map(|plans| &plans.tiers);
+
+        let mut store = ApiKeyStore {
+            source: config.source.clone(),
+            keys: HashMap::new(),
+        };
+        for (hash, key) in keys {
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("`{}` is not a hex encoded SHA-256", hash)));
+            }
+            if let Some(plan) = &key.plan
+                && !tiers.is_some_and(|tiers| tiers.contains_key(plan))
+            {
+                return Err(invalid(format!("unknown plan `{}` for `{}`", plan, key.id)));
+            }
+            store.keys.insert(hash.to_ascii_lowercase(), key);
+        }
+        Ok(Some(store))
+    }
+
+    pub fn load_jwt(&self) -> Result<Option<JwtValidator>, ConfigError> {
+        let Some(config) = &self.auth.jwt else {
+            return Ok(None);
+        };
+        let invalid = |message: String| {
+            let path: Vec<Segment> = vec!["auth".into(), "jwt".into(), "jwks_file".into()];
+            self.invalid(
+                &path,
+                format!("{}: {}", config.jwks_file.display(), message),
+            )
+        };
+        let source = std::fs::read(&config.jwks_file).map_err(|e| invalid(e.to_string()))?;
+        let jwks: JwkSet = serde_json::from_slice(&source).map_err(|e| invalid(e.to_string()))?;
+        let mut validator = JwtValidator::new(&jwks).map_err(invalid)?;
+        validator.issuer = config.issuer.clone();
+        validator.audience = config.audience.clone();
+        validator.leeway = config.leeway;
+        validator.key_claim = config.key_claim.clone();
+        validator.plan_claim = config.plan_claim.clone();
+        Ok(Some(validator))
+    }
+
+    pub fn load_hmac(&self) -> Result<Option<HmacVerifier>, ConfigError> {
+        let Some(config) = &self.auth.hmac else {
+            return Ok(None);
+        };
+        let at = |key: &str| -> Vec<Segment> { vec!["auth".into(), "hmac".into(), key.into()] };
+        let invalid = |message: String| {
+            let message = format!("{}: {}", config.secrets_file.display(), message);
+            self.invalid(&at("secrets_file"), message)
+        };
+        let source =
+            std::fs::read_to_string(&config.secrets_file).map_err(|e| invalid(e.to_string()))?;
+        let secrets: HashMap<String, PartnerSecret> =
+            serde_yaml::from_str(&source).map_err(|e| invalid(e.to_string()))?;
+        let tiers = self.plans.as_ref().map(|plans| &plans.tiers);
+        for (key_id, partner) in &secrets {
+            if let Some(plan) = &partner.plan
+                && !tiers.is_some_and(|tiers| tiers.contains_key(plan))
+            {
+                return Err(invalid(format!("unknown plan `{}` for `{}`", plan, key_id)));
+            }
+        }
+
+        let mut verifier = HmacVerifier::new(secrets);
+        let headers = [
+            (
+                "key_id_header",
+                &config.key_id_header,
+                &mut verifier.key_id_header,
+            ),
+            (
+                "signature_header",
+                &config.signature_header,
+                &mut verifier.signature_header,
+            ),
+            (
+                "timestamp_header",
+                &config.timestamp_header,
+                &mut verifier.timestamp_header,
+            ),
+            (
+                "nonce_header",
+                &config.nonce_header,
+                &mut verifier.nonce_header,
+            ),
+        ];
+        for (key, configured, header) in headers {
+            if let Some(name) = configured {
+                self.check_key(&at(key), &KeyExtractor::Header(name.clone()))?;
+                *header = name.to_ascii_lowercase();
+            }
+        }
+        if let Some(components) = &config.components {
+            verifier.components = components.clone();
+        }
+        verifier.clock_skew = config.clock_skew.unwrap_or(verifier.clock_skew);
+        verifier.max_body_size = config.max_body_size.unwrap_or(verifier.max_body_size);
+        Ok(Some(verifier))
+    }
+
+    pub fn load_introspection(&self) -> Result<Option<Introspector>, ConfigError> {
+        let Some(config) = &self.auth.introspection else {
+            return Ok(None);
+        };
+        let path: Vec<Segment> = vec!["auth".into(), "introspection".into(), "endpoint".into()];
+        let valid = reqwest::Url::parse(&config.endpoint)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+        if !valid {
+            let message = format!("`{}` is not an http(s) url", config.endpoint);
+            return Err(self.invalid(&path, message));
+        }
+        let timeout = config.timeout.unwrap_or(Duration::from_secs(2));
+        let mut introspector = Introspector::new(&config.endpoint, timeout)
+            .map_err(|message| self.invalid(&path, message))?;
+        introspector.client_id = config.client_id.clone();
+        introspector.client_secret = config.client_secret.clone();
+        introspector.active_ttl = config.active_ttl.unwrap_or(introspector.active_ttl);
+        introspector.inactive_ttl = config.inactive_ttl.unwrap_or(introspector.inactive_ttl);
+        introspector.plan_claim = config.plan_claim.clone();
+        if let Some(limit) = config.limit.as_ref().map(|name| &self.limits[name]) {
+            introspector.limiter = RateLimiter::new(limit.rate, limit.window.as_secs());
+        }
+        Ok(Some(introspector))
+    }
+
+    fn check_quota(&self, path: &[Segment], limit: &LimitConfig) -> Result<(), ConfigError> {
+        let at = |key: &str| -> Vec<Segment> {
+            let mut path = path.to_vec();
+            path.push(key.into());
+            path
+        };
+        if limit.rate == 0 {
+            return Err(self.invalid(&at("rate"), "must be at least 1"));
+        }
+        if limit.window < Duration::from_secs(1) || limit.window.subsec_nanos() != 0 {
+            return Err(self.invalid(&at("window"), "must be a whole number of seconds"));
+        }
+        self.check_duration(&at("window"), limit.window)
+    }
+
+    fn check_duration(&self, path: &[Segment], duration: Duration) -> Result<(), ConfigError> {
+        if duration > MAX_DURATION {
+            return Err(self.invalid(path, "must be at most 3650 days"));
+        }
+        Ok(())
+    }
+
+    // timers and timeouts of zero would fire continuously
+    fn check_interval(&self, path: &[Segment], duration: Duration) -> Result<(), ConfigError> {
+        if duration.is_zero() {
+            return Err(self.invalid(path, "must be more than 0"));
+        }
+        self.check_duration(path, duration)
+    }
+
+    fn check_timeouts(&self, path: &[Segment], timeouts: &Timeouts) -> Result<(), ConfigError> {
+        let timeouts = [
+            ("connect", timeouts.connect),
+            ("first_byte", timeouts.first_byte),
+            ("total", timeouts.total),
+            ("idle", timeouts.idle),
+        ];
+        for (key, timeout) in timeouts {
+            if let Some(timeout) = timeout {
+                let mut path = path.to_vec();
+                path.push(key.into());
+                self.check_interval(&path, timeout)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_key(&self, path: &[Segment], key: &KeyExtractor) -> Result<(), ConfigError> {
+        match key {
+            KeyExtractor::Header(name) if HeaderName::from_bytes(name.as_bytes()).is_err() => {
+                Err(self.invalid(path, format!("invalid header name `{}`", name)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_limit(&self, path: &[Segment], name: &str) -> Result<(), ConfigError> {
+        if self.limits.contains_key(name) {
+            Ok(())
+        } else {
+            Err(self.invalid(path, format!("unknown limit `{}`", name)))
+        }
+    }
+
+    pub fn invalid(&self, path: &[Segment], message: impl Into<String>) -> ConfigError {
+        let mut display = String::new();
+        for segment in path {
+            match segment {
+                Segment::Key(key) if display.is_empty() => display.push_str(key),
+                Segment::Key(key) => display.push_str(&format!(".{}", key)),
+                Segment::Index(index) => display.push_str(&format!("[{}]", index)),
+            }
+        }
+        ConfigError::Invalid {
+            path: display,
+            line: line_of(&self.source, path),
+            message: message.into(),
+        }
+    }
+}
+
+enum Token<'a> {
+    Key(&'a str),
+    Item, // the dash of a sequence item
+}
+
+// 1-based line of a value in block style YAML, or of its closest parent found,
+// flow style collections and multi-line strings are not looked into
+pub fn line_of(source: &str, path: &[Segment]) -> Option<usize> {
+    let tokens = tokenize(source);
+    let mut cursor = 0;
+    let mut min_indent: usize = 0;
+    let mut line = None;
+    for segment in path {
+        // a sequence may be indented as much as the key holding it
+        let floor = match segment {
+            Segment::Index(_) => min_indent.saturating_sub(1),
+            Segment::Key(_) => min_indent,
+        };
+        let mut level = None;
+        let mut items = 0;
+        let mut found = None;
+        for (position, (_, indent, token)) in tokens.iter().enumerate().skip(cursor) {
+            if *indent < floor || level.is_some_and(|level| *indent < level) {
+                break;
+            }
+            let level = *level.get_or_insert(*indent);
+            if *indent != level {
+                continue;
+            }
+            let matched = match (segment, token) {
+                (Segment::Key(key), Token::Key(name)) => key == name,
+                (Segment::Index(index), Token::Item) => {
+                    items += 1;
+                    items - 1 == *index
+                }
+                (Segment::Index(_), Token::Key(_)) => break,
+                _ => false,
+            };
+            if matched {
+                found = Some(position);
+                break;
+            }
+        }
+        let Some(position) = found else {
+            break;
+        };
+        let (n, indent, _) = tokens[position];
+        line = Some(n + 1);
+        cursor = position + 1;
+        min_indent = indent + 1;
+    }
+    line
+}
+
+// (line, indent, token), a line such as `- name: pets` gives an item and a key
+fn tokenize(source: &str) -> Vec<(usize, usize, Token<'_>)> {
+    let mut tokens = vec![];
+    for (n, line) in source.lines().enumerate() {
+        let mut indent = line.len() - line.trim_start().len();
+        let mut rest = line.trim_start();
+        while rest == "-" || rest.starts_with("- ") {
+            tokens.push((n, indent, Token::Item));
+            let content = rest[1..].trim_start();
+            indent += rest.len() - content.len();
+            rest = content;
+        }
+        if rest.is_empty() || rest.starts_with('#') || rest.starts_with("---") {
+            continue;
+        }
+        let key = match rest.split_once(": ") {
+            Some((key, _)) => Some(key),
+            None => rest.strip_suffix(':'),
+        };
+        if let Some(key) = key {
+            tokens.push((n, indent, Token::Key(key.trim().trim_matches(['"', '\'']))));
+        }
+    }
+    tokens
+}
+
+// the name of the auth section an upstream method needs
+fn auth_name(method: Option<AuthMethod>) -> &'static str {
+    match method {
+        Some(AuthMethod::ApiKey) => "api_keys",
+        Some(AuthMethod::Jwt) => "jwt",
+        Some(AuthMethod::Hmac) => "hmac",
+        Some(AuthMethod::Introspection) => "introspection",
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::upstream_pool::LoadBalancing;
+
+    const CONFIG: &str = "
+listeners:
+  http:
+    address: 127.0.0.1:8080
+limits:
+  default:
+    rate: 5
+    window: 1m
+upstreams:
+  - name: pets
+    urls:
+      - http://pets-1:8080
+      - http://pets-2:8080
+    load_balancing: least_outstanding
+    limit: default
+    key: !header x-api-key
+    timeouts:
+      first_byte: 500ms
+    cache: {}
+routes:
+  - path_prefix: /pets
+    upstream: pets
+";
+
+    #[test]
+    fn test_parse_with_defaults() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        assert_eq!(config.listeners.http.address.port(), 8080);
+        assert!(config.listeners.https.is_none());
+        assert_eq!(config.limits["default"].window, Duration::from_secs(60));
+        let upstream = &config.upstreams[0];
+        assert_eq!(upstream.load_balancing, LoadBalancing::LeastOutstanding);
+        assert_eq!(upstream.key, KeyExtractor::Header("x-api-key".to_string()));
+        assert_eq!(
+            upstream.timeouts.first_byte,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(upstream.timeouts.total, None);
+        assert_eq!(upstream.cache.as_ref().unwrap().max_entries, 1000);
+        assert_eq!(upstream.retry.max_retries, 2);
+        assert_eq!(config.routes[0].upstream, "pets");
+        assert!(config.forwarding.x_forwarded_for);
+    }
+
+    #[test]
+    fn test_errors_point_at_the_line() {
+        let unknown_limit = CONFIG.replace("limit: default", "limit: premium");
+        let err = Config::parse(&unknown_limit).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 15 (upstreams[0].limit): unknown limit `premium`"
+        );
+
+        let second_url = CONFIG.replace("http://pets-2:8080", "pets-2");
+        let err = Config::parse(&second_url).unwrap_err().to_string();
+        assert!(err.starts_with("Invalid configuration at line 13 (upstreams[0].urls[1])"));
+
+        let bad_rule = CONFIG.replace(
+            "    cache: {}\n",
+            "    rules:\n      - when: path.starts_with(\"/search\") &&\n        limit: default\n",
+        );
+        let err = Config::parse(&bad_rule).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 20 (upstreams[0].rules[0].when): \
+             expected an operand at column 31"
+        );
+
+        // serde reports the line of values it cannot parse
+        let bad_window = CONFIG.replace("window: 1m", "window: 1 minute");
+        let err = Config::parse(&bad_window).unwrap_err().to_string();
+        assert!(err.contains("line 8"), "{}", err);
+    }
+
+    #[test]
+    fn test_durations_are_range_checked() {
+        let zero_interval = format!("{}reload:\n  interval: 0\n", CONFIG);
+        let err = Config::parse(&zero_interval).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 24 (reload.interval): must be more than 0"
+        );
+
+        let zero_timeout = CONFIG.replace("first_byte: 500ms", "first_byte: 0ms");
+        let err = Config::parse(&zero_timeout).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 18 (upstreams[0].timeouts.first_byte): \
+             must be more than 0"
+        );
+
+        let huge_window = CONFIG.replace("window: 1m", "window: 99999999999999");
+        let err = Config::parse(&huge_window).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 8 (limits.default.window): must be at most 3650 days"
+        );
+
+        let health_check = CONFIG.replace(
+            "    cache: {}\n",
+            "    cache: {}\n    health_check:\n      path: /health\n      interval: 0s\n",
+        );
+        let err = Config::parse(&health_check).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Invalid configuration at line 22 (upstreams[0].health_check.interval): \
+             must be more than 0"
+        );
+    }
+
+    #[test]
+    fn test_load_plans() {
+        let path = std::env::temp_dir().join(format!("plans-{}.yml", std::process::id()));
+        let keys = "
+tenant-1: { plan: pro }
+tenant-2: { plan: pro, rate: 500 }
+revoked: { plan: free, rate: 0 }
+";
+        std::fs::write(&path, keys).unwrap();
+        let plans = format!(
+            "
+plans:
+  tiers:
+    free: {{ rate: 10, window: 1m }}
+    pro: {{ rate: 100, window: 1m }}
+  keys_file: {}
+  default: free",
+            path.display()
+        );
+        let config = Config::parse(&format!("{}{}", plans, CONFIG)).unwrap();
+        let plans = config.load_plans().unwrap().unwrap();
+        let rate = |key: &str| {
+            plans
+                .quota(key, None)
+                .map(|quota| (quota.rate, quota.window))
+        };
+
+        assert_eq!(rate("tenant-1"), Some((100, 60)));
+        assert_eq!(rate("tenant-2"), Some((500, 60)));
+        assert_eq!(rate("revoked"), Some((0, 60)));
+        assert_eq!(rate("anyone"), Some((10, 60)));
+
+        std::fs::write(&path, "tenant-1: { plan: pro, window: 1500ms }\n").unwrap();
+        let err = config.load_plans().unwrap_err().to_string();
+        assert!(
+            err.contains("the window of `tenant-1` must be a whole number of seconds"),
+            "{}",
+            err
+        );
+
+        std::fs::write(&path, "tenant-1: { plan: gold }\n").unwrap();
+        let err = config.load_plans().unwrap_err().to_string();
+        assert!(
+            err.contains("unknown plan `gold` for `tenant-1`"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,5 @@
+pub mod de;
+pub mod loader;
+pub mod model;
+pub mod reload;
+pub mod runtime;
@@ -0,0 +1,295 @@
+use crate::api::api_keys::ApiKeySource;
+use crate::api::auth::AuthMethod;
+use crate::api::cache::CacheConfig;
+use crate::api::circuit_breaker::CircuitBreakerConfig;
+use crate::api::coalescing::CoalescingConfig;
+use crate::api::forwarding::ForwardingConfig;
+use crate::api::idempotency::IdempotencyConfig;
+use crate::api::key_extractor::KeyExtractor;
+use crate::api::protocol::UpstreamProtocol;
+use crate::api::retry::RetryConfig;
+use crate::api::signatures::SignedComponent;
+use crate::api::timeouts::Timeouts;
+use crate::api::upstream_pool::{HealthCheck, LoadBalancing};
+use crate::api::upstream_tls::UpstreamTlsConfig;
+use crate::server::tls::CertificateConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// The whole proxy configuration, as written in the YAML file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: ListenersConfig,
+    pub forwarding: ForwardingConfig,
+    pub limits: BTreeMap<String, LimitConfig>, // by name, referenced by the upstreams
+    pub plans: Option<PlansConfig>,
+    pub auth: AuthConfig,
+    pub upstreams: Vec<UpstreamConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub reload: ReloadConfig,
+    pub shutdown: ShutdownConfig,
+    #[serde(skip)]
+    pub source: String, // the YAML text, to point errors at a line
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenersConfig {
+    pub http: HttpListenerConfig,
+    pub https: Option<HttpsListenerConfig>, // None disables HTTPS
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpListenerConfig {
+    pub address: SocketAddr,
+}
+
+impl Default for HttpListenerConfig {
+    fn default() -> Self {
+        HttpListenerConfig {
+            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpsListenerConfig {
+    #[serde(default = "HttpsListenerConfig::default_address")]
+    pub address: SocketAddr,
+    pub certificates: Vec<CertificateConfig>, // the first one is served when SNI does not match
+    #[serde(
+        default = "HttpsListenerConfig::default_reload_interval",
+        deserialize_with = "crate::config::de::duration"
+    )]
+    pub reload_interval: Duration,
+}
+
+impl HttpsListenerConfig {
+    fn default_address() -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0], 3443))
+    }
+
+    fn default_reload_interval() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+// SIGHUP always reloads the configuration, file changes only when watched
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadConfig {
+    pub watch: bool,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub interval: Duration, // how often the file is checked for changes
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig {
+            watch: true,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub grace_period: Duration, // for requests in flight once SIGTERM is received
+    pub readiness_path: Option<String>, // answered ahead of the routes, off by default
+    pub state_file: Option<PathBuf>, // limiter counters are saved there on exit, restored on start
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period: Duration::from_secs(30),
+            readiness_path: None,
+            state_file: None,
+        }
+    }
+}
+
+// A number of requests allowed per window, for each key
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    pub rate: u64,
+    #[serde(deserialize_with = "crate::config::de::duration")]
+    pub window: Duration, // whole seconds
+}
+
+// Customer plans, the upstreams using them count each listed key against its plan
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlansConfig {
+    pub tiers: BTreeMap<String, LimitConfig>, // by name, e.g. free, pro and enterprise
+    pub keys_file: PathBuf,                   // the plan of each customer key, read on (re)load
+    pub default: Option<String>,              // None keeps the limit of the upstream
+}
+
+// Credentials the upstreams can require, each upstream picks its method
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys: Option<ApiKeysConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub hmac: Option<HmacConfig>,
+    pub introspection: Option<IntrospectionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeysConfig {
+    pub source: ApiKeySource, // !header x-api-key or !query api_key
+    pub store_file: PathBuf,  // keys by their SHA-256, read on (re)load
+}
+
+// Bearer tokens, checked against the keys of a JWKS file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub jwks_file: PathBuf, // read on (re)load
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    #[serde(
+        default = "JwtConfig::default_leeway",
+        deserialize_with = "crate::config::de::duration"
+    )]
+    pub leeway: Duration,
+    #[serde(default = "JwtConfig::default_key_claim")]
+    pub key_claim: String, // the rate-limit key, e.g. sub or org_id
+    pub plan_claim: Option<String>, // the plan, when the upstream uses plans
+}
+
+impl JwtConfig {
+    fn default_leeway() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_key_claim() -> String {
+        "sub".to_string()
+    }
+}
+
+// Partner requests signed with HMAC-SHA256, unset values take the defaults
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HmacConfig {
+    pub secrets_file: PathBuf, // key ids to `{ secret, plan }`, read on (re)load
+    pub key_id_header: Option<String>, // x-key-id
+    pub signature_header: Option<String>, // x-signature, hex encoded
+    pub timestamp_header: Option<String>, // x-timestamp, in unix seconds
+    pub nonce_header: Option<String>, // x-nonce
+    pub components: Option<Vec<SignedComponent>>, // method, path, timestamp, nonce, body_sha256
+    #[serde(default, deserialize_with = "crate::config::de::option_duration")]
+    pub clock_skew: Option<Duration>, // 5m
+    pub max_body_size: Option<usize>, // 1 MiB
+}
+
+// Opaque bearer tokens, checked by an RFC 7662 endpoint of the authorization server
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IntrospectionConfig {
+    pub endpoint: String,
+    pub client_id: Option<String>, // basic auth of the proxy to the endpoint
+    pub client_secret: Option<String>,
+    #[serde(default, deserialize_with = "crate::config::de::option_duration")]
+    pub active_ttl: Option<Duration>, // 60s, how long active tokens are remembered
+    #[serde(default, deserialize_with = "crate::config::de::option_duration")]
+    pub inactive_ttl: Option<Duration>, // 10s
+    #[serde(default, deserialize_with = "crate::config::de::option_duration")]
+    pub timeout: Option<Duration>, // 2s
+    pub plan_claim: Option<String>, // the plan, when the upstream uses plans
+    pub limit: Option<String>, // lookups of unknown tokens per client IP, 60 a minute by default
+}
+
+// An entry of the keys file, e.g. `tenant-42: { plan: pro, rate: 500 }`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanKeyConfig {
+    pub plan: String,
+    pub rate: Option<u64>, // overrides the plan for this key, 0 blocks it
+    #[serde(default, deserialize_with = "crate::config::de::option_duration")]
+    pub window: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    pub limit: String, // name of the limit
+    #[serde(default)]
+    pub key: KeyExtractor, // forwarded_for, client_ip or !header x-api-key
+    #[serde(default)]
+    pub timeouts: Timeouts, // unset values take the defaults
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    pub tls: Option<UpstreamTlsConfig>,
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub cache: Option<CacheConfig>, // `cache: {}` enables it with the defaults
+    pub coalescing: Option<CoalescingConfig>,
+    pub idempotency: Option<IdempotencyConfig>,
+    #[serde(default)]
+    pub websocket: WebSocketLimits,
+    #[serde(default)]
+    pub grpc: GrpcLimits,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>, // tried in order, the first match applies
+    #[serde(default)]
+    pub plans: bool, // customer plans replace the limits of the upstream and its rules
+    pub auth: Option<AuthMethod>, // None lets every request through
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub when: String,  // e.g. header("x-plan") == "free" && path.starts_with("/search")
+    pub limit: String, // name of the limit
+    pub key: Option<KeyExtractor>, // None keeps the key of the upstream
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketLimits {
+    pub message_limit: Option<String>, // name of the limit on client messages
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcLimits {
+    pub method_limits: BTreeMap<String, String>, // method name to limit name
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub host: Option<String>,
+    #[serde(default = "RouteConfig::default_path_prefix")]
+    pub path_prefix: String,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    pub upstream: String,
+    #[serde(default)]
+    pub timeouts: Timeouts, // override the upstream ones
+}
+
+impl RouteConfig {
+    fn default_path_prefix() -> String {
+        "/".to_string()
+    }
+}
@@ -0,0 +1,199 @@
+use crate::api::http_proxy::HttpProxy;
+use crate::cli::ConfigArgs;
+use crate::config::loader::ConfigError;
+use crate::config::model::{Config, ReloadConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::signal::unix::{SignalKind, signal};
+
+// The proxy serving new requests, replaced as a whole on reload,
+// requests in flight finish with the one they started with
+#[derive(Clone)]
+pub struct SharedProxy(Arc<RwLock<Arc<HttpProxy>>>);
+
+impl SharedProxy {
+    pub fn new(proxy: HttpProxy) -> SharedProxy {
+        SharedProxy(Arc::new(RwLock::new(Arc::new(proxy))))
+    }
+
+    pub fn current(&self) -> Arc<HttpProxy> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, proxy: HttpProxy) {
+        *self.0.write().unwrap() = Arc::new(proxy);
+    }
+}
+
+pub struct Reloader {
+    args: ConfigArgs,
+    proxy: SharedProxy,
+    listeners: (SocketAddr, Option<SocketAddr>), // bound at startup, kept until a restart
+}
+
+impl Reloader {
+    pub fn new(args: ConfigArgs, proxy: SharedProxy, config: &Config) -> Reloader {
+        Reloader {
+            args,
+            proxy,
+            listeners: listeners(config),
+        }
+    }
+
+    // the new configuration is fully built before anything is replaced,
+    // on any error the current one stays in place
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = self.args.load()?;
+        let runtime = config.build()?;
+        if listeners(&config) != self.listeners {
+            println!("Listener changes are applied on restart");
+        }
+
+        let current = self.proxy.current();
+        let mut routing = runtime.routing;
+        routing.carry_over(&current.routing);
+        routing.spawn_health_checks(&current.client);
+        self.proxy.replace(HttpProxy {
+            routing: Arc::new(routing),
+            client: current.client.clone(),
+            forwarding: runtime.forwarding,
+        });
+        Ok(())
+    }
+
+    // reloads on SIGHUP, and when the file changes if it is watched
+    pub fn spawn(self, config: &ReloadConfig) {
+        let watch = config.watch;
+        let interval = config.interval;
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(err) => {
+                    println!("Reload on SIGHUP disabled: {}", err);
+                    return;
+                }
+            };
+            let mut ticker = tokio::time::interval(interval);
+            let mut last_modified = modified(&self.args.config);
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => println!("SIGHUP received, reloading the configuration"),
+                    _ = ticker.tick(), if watch => {
+                        let current = modified(&self.args.config);
+                        if current == last_modified {
+                            continue;
+                        }
+                        last_modified = current;
+                        println!("{} changed, reloading it", self.args.config.display());
+                    }
+                }
+                match self.reload() {
+                    Ok(()) => println!("Configuration reloaded"),
+                    Err(err) => println!("Reload failed, the configuration is unchanged: {}", err),
+                }
+            }
+        });
+    }
+}
+
+fn listeners(config: &Config) -> (SocketAddr, Option<SocketAddr>) {
+    let https = config.listeners.https.as_ref();
+    (
+        config.listeners.http.address,
+        https.map(|https| https.address),
+    )
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::forwarding::ForwardingConfig;
+    use crate::api::idempotency::Begin;
+    use crate::api::routing::RoutingTable;
+
+    fn config(rate: u64) -> String {
+        format!(
+            "
+limits:
+  default: {{ rate: {}, window: 60 }}
+upstreams:
+  - name: pets
+    urls: [http://pets:8080]
+    limit: default
+    idempotency: {{}}
+routes:
+  - upstream: pets
+",
+            rate
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reload_carries_over_unchanged_limits() {
+        let path = std::env::temp_dir().join(format!("reload-{}.yml", std::process::id()));
+        std::fs::write(&path, config(1)).unwrap();
+        let args = ConfigArgs {
+            config: path.clone(),
+            http_address: None,
+            https_address: None,
+        };
+        let loaded = args.load().unwrap();
+        let proxy = SharedProxy::new(HttpProxy {
+            routing: Arc::new(RoutingTable::new(vec![], vec![])),
+            client: reqwest::Client::new(),
+            forwarding: ForwardingConfig::default(),
+        });
+        let reloader = Reloader::new(args, proxy.clone(), &loaded);
+        reloader.reload().unwrap();
+        let is_authorized = |proxy: &SharedProxy| {
+            let route = proxy.current().routing.resolve(None, "/pets").unwrap();
+            route
+                .upstream
+                .rate_limiter
+                .is_authorized(&"1.0.0.0".to_string())
+        };
+        assert!(is_authorized(&proxy));
+        let upstream = |proxy: &SharedProxy| {
+            proxy
+                .current()
+                .routing
+                .resolve(None, "/pets")
+                .unwrap()
+                .upstream
+        };
+        let pets = upstream(&proxy);
+        let Begin::Proceed(_guard) = pets.idempotency.as_ref().unwrap().begin("order-1") else {
+            panic!("expected a new idempotency key");
+        };
+
+        // the same limit keeps its counters
+        reloader.reload().unwrap();
+        assert!(!is_authorized(&proxy));
+        // as does the rest of the upstream state, the key is still being processed
+        let reloaded = upstream(&proxy);
+        assert!(Arc::ptr_eq(
+            &pets.pool.backends()[0],
+            &reloaded.pool.backends()[0]
+        ));
+        let idempotency = reloaded.idempotency.as_ref().unwrap();
+        assert!(matches!(idempotency.begin("order-1"), Begin::Conflict));
+
+        // an invalid file leaves the proxy untouched
+        let current = proxy.current();
+        std::fs::write(&path, config(0)).unwrap();
+        assert!(reloader.reload().is_err());
+        assert!(Arc::ptr_eq(&current, &proxy.current()));
+
+        // a new limit starts from scratch
+        std::fs::write(&path, config(2)).unwrap();
+        reloader.reload().unwrap();
+        assert!(is_authorized(&proxy));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
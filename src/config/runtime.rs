@@ -0,0 +1,216 @@
+use crate::api::auth::{AuthMethod, Authenticator};
+use crate::api::cache::ResponseCache;
+use crate::api::circuit_breaker::CircuitBreaker;
+use crate::api::coalescing::RequestCoalescer;
+use crate::api::forwarding::ForwardingConfig;
+use crate::api::idempotency::IdempotencyStore;
+use crate::api::retry::RetryPolicy;
+use crate::api::routing::{Route, RoutingTable, Upstream};
+use crate::api::rules::{Condition, LimitRule};
+use crate::api::timeouts::Timeouts;
+use crate::api::upstream_pool::UpstreamPool;
+use crate::config::loader::{ConfigError, Segment};
+use crate::config::model::{Config, UpstreamConfig};
+use crate::engine::plans::Plans;
+use crate::engine::rate_limiter::RateLimiter;
+use crate::server::tls::TlsConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// What the proxy runs with, built from a validated configuration
+pub struct Runtime {
+    pub http: SocketAddr,
+    pub https: Option<(SocketAddr, TlsConfig)>,
+    pub routing: RoutingTable,
+    pub forwarding: ForwardingConfig,
+}
+
+impl Config {
+    pub fn build(&self) -> Result<Runtime, ConfigError> {
+        let plans = self.load_plans()?.map(Arc::new);
+        let api_keys = self.load_api_keys()?.map(Arc::new);
+        let jwt = self.load_jwt()?.map(Arc::new);
+        let hmac = self.load_hmac()?.map(Arc::new);
+        let introspection = self.load_introspection()?.map(Arc::new);
+        let authenticator = |method: Option<AuthMethod>| match method {
+            Some(AuthMethod::ApiKey) => api_keys.clone().map(Authenticator::ApiKey),
+            Some(AuthMethod::Jwt) => jwt.clone().map(Authenticator::Jwt),
+            Some(AuthMethod::Hmac) => hmac.clone().map(Authenticator::Hmac),
+            Some(AuthMethod::Introspection) => {
+                introspection.clone().map(Authenticator::Introspection)
+            }
+            None => None,
+        };
+        let upstreams = self
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(i, upstream)| {
+                self.build_upstream(i, upstream, plans.as_ref(), authenticator(upstream.auth))
+            })
+            .collect::<Result<Vec<Upstream>, ConfigError>>()?;
+        let routes = self
+            .routes
+            .iter()
+            .map(|config| {
+                let mut route = Route::new(
+                    config.host.as_deref(),
+                    &config.path_prefix,
+                    &config.upstream,
+                );
+                route.strip_prefix = config.strip_prefix;
+                route.timeouts = config.timeouts.clone();
+                route
+            })
+            .collect();
+
+        Ok(Runtime {
+            http: self.listeners.http.address,
+            https: self.listeners.https.as_ref().map(|https| {
+                let tls_config = TlsConfig {
+                    certificates: https.certificates.clone(),
+                    reload_interval: https.reload_interval,
+                };
+                (https.address, tls_config)
+            }),
+            routing: RoutingTable::new(upstreams, routes),
+            forwarding: self.forwarding.clone(),
+        })
+    }
+
+    // a limit referenced by several upstreams is counted separately for each of them
+    fn rate_limiter(&self, name: &str) -> RateLimiter {
+        let limit = &self.limits[name];
+        RateLimiter::new(limit.rate, limit.window.as_secs())
+    }
+
+    fn build_upstream(
+        &self,
+        i: usize,
+        config: &UpstreamConfig,
+        plans: Option<&Arc<Plans>>,
+        authenticator: Option<Authenticator>,
+    ) -> Result<Upstream, ConfigError> {
+        let mut upstream = Upstream::new(
+            &config.name,
+            &config.urls[0],
+            self.rate_limiter(&config.limit),
+        );
+        let urls: Vec<&str> = config.urls.iter().map(|url| url.as_str()).collect();
+        upstream.pool = UpstreamPool::new(&urls, config.load_balancing);
+        upstream.pool.health_check = config.health_check.clone();
+        upstream.key_extractor = config.key.clone();
+        upstream.timeouts = config.timeouts.or(&Timeouts::with_defaults());
+        upstream.circuit_breaker = CircuitBreaker::new(config.circuit_breaker.clone());
+        upstream.retry = RetryPolicy::new(config.retry.clone());
+        upstream.websocket.message_limiter = config
+            .websocket
+            .message_limit
+            .as_ref()
+            .map(|name| self.rate_limiter(name));
+        for (method, limit) in &config.grpc.method_limits {
+            upstream
+                .grpc
+                .method_limiters
+                .insert(method.clone(), self.rate_limiter(limit));
+        }
+        for (j, rule) in config.rules.iter().enumerate() {
+            let condition = Condition::parse(&rule.when).map_err(|e| {
+                let path: Vec<Segment> = vec![
+                    "upstreams".into(),
+                    i.into(),
+                    "rules".into(),
+                    j.into(),
+                    "when".into(),
+                ];
+                self.invalid(&path, e.to_string())
+            })?;
+            upstream.rules.push(LimitRule {
+                when: rule.when.clone(),
+                condition,
+                rate_limiter: self.rate_limiter(&rule.limit),
+                key_extractor: rule.key.clone().unwrap_or_else(|| config.key.clone()),
+            });
+        }
+        if config.plans {
+            upstream.rate_limiter.plans = plans.cloned();
+            for rule in upstream.rules.iter_mut() {
+                rule.rate_limiter.plans = plans.cloned();
+            }
+        }
+        upstream.authenticator = authenticator;
+        upstream.cache = config.cache.clone().map(ResponseCache::new);
+        upstream.coalescing = config.coalescing.clone().map(RequestCoalescer::new);
+        upstream.idempotency = config.idempotency.clone().map(IdempotencyStore::new);
+
+        // each upstream gets its own client, for its connect timeout, protocol and TLS settings
+        let upstream = match &config.tls {
+            Some(tls) => {
+                upstream.protocol = config.protocol;
+                upstream.with_tls(tls)
+            }
+            None => upstream.with_protocol(config.protocol),
+        };
+        upstream.map_err(|e| {
+            let path: Vec<Segment> = vec!["upstreams".into(), i.into(), "tls".into()];
+            self.invalid(&path, e.to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::forwarding::ClientInfo;
+    use crate::api::key_extractor::KeyExtractor;
+    use crate::api::rules::RequestFacts;
+    use axum::http::Request;
+
+    #[test]
+    fn test_build_runtime() {
+        let config = Config::parse(
+            r#"
+limits:
+  default: { rate: 5, window: 60 }
+  messages: { rate: 100, window: 1m }
+upstreams:
+  - name: pets
+    urls: [http://pets:8080]
+    limit: default
+    websocket:
+      message_limit: messages
+    rules:
+      - when: header("x-plan") == "free"
+        limit: messages
+        key: client_ip
+routes:
+  - upstream: pets
+"#,
+        )
+        .unwrap();
+        let runtime = config.build().unwrap();
+
+        assert_eq!(runtime.http, "0.0.0.0:3000".parse().unwrap());
+        assert!(runtime.https.is_none());
+        let route = runtime.routing.resolve(None, "/pets/1").unwrap();
+        assert_eq!(route.upstream.rate_limiter.rate, 5);
+        assert_eq!(route.upstream.rate_limiter.window, 60);
+        assert!(route.upstream.client.is_some());
+        let message_limiter = route.upstream.websocket.message_limiter.as_ref();
+        assert_eq!(message_limiter.unwrap().rate, 100);
+        assert_eq!(route.timeouts, Timeouts::with_defaults());
+
+        let client = ClientInfo::default();
+        let free = Request::get("/pets")
+            .header("x-plan", "free")
+            .body(())
+            .unwrap();
+        let facts = RequestFacts::new(&free, &client, &runtime.forwarding);
+        let (limiter, key) = route.upstream.limit_for(&facts);
+        assert_eq!((limiter.rate, key), (100, &KeyExtractor::ClientIp));
+        let paid = Request::get("/pets").body(()).unwrap();
+        let facts = RequestFacts::new(&paid, &client, &runtime.forwarding);
+        let (limiter, key) = route.upstream.limit_for(&facts);
+        assert_eq!((limiter.rate, key), (5, &KeyExtractor::ForwardedFor));
+    }
+}
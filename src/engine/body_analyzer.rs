@@ -1,11 +1,32 @@
 use std::fs;
+use std::path::Path;
 
 use axum::{
     body::{Body, to_bytes},
     extract::{Request, path},
 };
 
-use crate::engine::model::BodyAnalyzerError;
+use crate::engine::model::{BodyAnalyzerError, OpenApiError};
+
+pub const OPEN_API_FILE: &str = "src/resources/openapi.yml";
+
+const HTTP_VERBS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+// What the OpenAPI spec describes, for operators to check it
+#[derive(Debug)]
+pub struct OpenApiReport {
+    pub title: String,
+    pub version: String,
+    pub routes: Vec<OpenApiRoute>,
+    pub schemas: Vec<String>, // names of the component schemas
+}
+
+#[derive(Debug)]
+pub struct OpenApiRoute {
+    pub path: String,
+    pub verbs: Vec<String>, // uppercase, in the order of the spec
+    pub request_bodies: Vec<(String, String, String)>, // verb, content type, schema name
+}
 
 pub struct BodyAnalyzer {
     limit_body_size: usize, // this value must be calculated in advance by a ML model
@@ -13,7 +34,7 @@ pub struct BodyAnalyzer {
 
 impl BodyAnalyzer {
     pub fn analyze_open_api_description() -> () {
-        let open_api_file = "src/resources/openapi.yml";
+        let open_api_file = OPEN_API_FILE;
         let openapi_spec = fs::read_to_string(open_api_file)
             .expect(format!("Failed to read file {}", open_api_file).as_str());
         let serde_yaml = serde_yaml::from_str::<serde_yaml::Value>(&openapi_spec)
@@ -71,6 +92,52 @@ impl BodyAnalyzer {
         });
     }
 
+    pub fn describe_open_api(spec: &Path) -> Result<OpenApiReport, OpenApiError> {
+        let source = fs::read_to_string(spec).map_err(OpenApiError::Io)?;
+        let spec = serde_yaml::from_str::<serde_yaml::Value>(&source).map_err(OpenApiError::Parse)?;
+        let text = |value: &serde_yaml::Value| value.as_str().unwrap_or_default().to_string();
+
+        let paths = spec["paths"].as_mapping().ok_or(OpenApiError::MissingPaths)?;
+        let mut routes = vec![];
+        for (path, operations) in paths {
+            let mut route = OpenApiRoute {
+                path: text(path),
+                verbs: vec![],
+                request_bodies: vec![],
+            };
+            // path items also hold parameters and descriptions
+            for verb in operations.as_mapping().into_iter().flat_map(|o| o.keys()) {
+                let verb = text(verb);
+                if !HTTP_VERBS.contains(&verb.as_str()) {
+                    continue;
+                }
+                let content = operations[verb.as_str()]["requestBody"]["content"].as_mapping();
+                for (content_type, media) in content.into_iter().flatten() {
+                    // "#/components/schemas/Pet" gives "Pet"
+                    let schema = match media["schema"]["$ref"].as_str() {
+                        Some(schema_ref) => schema_ref.rsplit('/').next().unwrap_or(schema_ref),
+                        None => "inline schema",
+                    };
+                    route.request_bodies.push((
+                        verb.to_uppercase(),
+                        text(content_type),
+                        schema.to_string(),
+                    ));
+                }
+                route.verbs.push(verb.to_uppercase());
+            }
+            routes.push(route);
+        }
+
+        let schemas = spec["components"]["schemas"].as_mapping();
+        Ok(OpenApiReport {
+            title: text(&spec["info"]["title"]),
+            version: text(&spec["info"]["version"]),
+            routes,
+            schemas: schemas.into_iter().flat_map(|s| s.keys()).map(text).collect(),
+        })
+    }
+
     pub async fn analyze_body_size<T>(
         &self,
         req: Request<Body>,
@@ -121,10 +188,25 @@ impl BodyAnalyzer {
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::body_analyzer::BodyAnalyzer;
+    use crate::engine::body_analyzer::{BodyAnalyzer, OPEN_API_FILE};
+    use std::path::Path;
 
     #[test]
     fn test_yaml_parsing() {
         BodyAnalyzer::analyze_open_api_description();
     }
+
+    #[test]
+    fn test_describe_open_api() {
+        let report = BodyAnalyzer::describe_open_api(Path::new(OPEN_API_FILE)).unwrap();
+
+        assert_eq!(report.title, "Swagger Petstore");
+        let pets = report.routes.iter().find(|route| route.path == "/pets").unwrap();
+        assert_eq!(pets.verbs, vec!["GET", "POST"]);
+        assert_eq!(
+            pets.request_bodies,
+            vec![("POST".to_string(), "application/json".to_string(), "Pet".to_string())]
+        );
+        assert!(report.schemas.contains(&"Error".to_string()));
+    }
 }
@@ -1,3 +1,4 @@
 pub mod rate_limiter;
 pub mod body_analyzer;
-pub mod model;
\ No newline at end of file
+pub mod model;
+pub mod plans;
\ No newline at end of file
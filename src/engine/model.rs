@@ -1,3 +1,6 @@
+use std::fmt;
+use std::io;
+
 pub enum EngineError {
     BodyAnalyzer(BodyAnalyzerError),
 }
@@ -6,3 +9,20 @@ pub enum BodyAnalyzerError {
     BodySizeExceeded,
 }
 
+
+#[derive(Debug)]
+pub enum OpenApiError {
+    Io(io::Error),
+    Parse(serde_yaml::Error),
+    MissingPaths,
+}
+
+impl fmt::Display for OpenApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Failed to read the spec: {}", e),
+            Self::Parse(e) => write!(f, "Invalid spec: {}", e),
+            Self::MissingPaths => write!(f, "The spec has no paths"),
+        }
+    }
+}
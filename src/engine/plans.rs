@@ -0,0 +1,24 @@
+use std::collections::HashMap;
+
+// Requests per window, in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub rate: u64,
+    pub window: u64,
+}
+
+// The quota of each customer key, from its plan with its overrides applied
+#[derive(Debug, Default, PartialEq)]
+pub struct Plans {
+    pub tiers: HashMap<String, Quota>, // by plan name, e.g. free, pro and enterprise
+    pub quotas: HashMap<String, Quota>, // by client key, e.g. an API key or a tenant id
+    pub default: Option<Quota>,        // for keys not listed, None keeps the limiter's own
+}
+
+impl Plans {
+    // a listed key keeps its own quota, others take the plan they authenticated with
+    pub fn quota(&self, key: &str, plan: Option<&str>) -> Option<Quota> {
+        let tier = plan.and_then(|plan| self.tiers.get(plan));
+        self.quotas.get(key).or(tier).copied().or(self.default)
+    }
+}
@@ -1,17 +1,30 @@
+use crate::engine::plans::{Plans, Quota};
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use futures::future::join_all;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{collections::HashMap, thread};
 use tokio::task::JoinHandle;
 
+// The counters of a limiter, saved on shutdown and restored on start
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LimiterState {
+    pub rate: u64,
+    pub window: u64,
+    pub visits: HashMap<String, Vec<i64>>, // unix timestamps in milliseconds, by key
+}
+
+// clones share their counters
 #[derive(Clone)]
 pub struct RateLimiter {
-    pub rate: u64,   // number of requests per window
-    pub window: u64, // window duration (in seconds), TODO: make it chrono::Duration
-    cache: DashMap<String, Vec<DateTime<Utc>>>, // TODO: the vec is not safe for high concurrency
-    _cache: HashMap<String, String>
+    pub rate: u64,                 // number of requests per window
+    pub window: u64,               // window duration (in seconds), TODO: make it chrono::Duration
+    pub plans: Option<Arc<Plans>>, // quotas per customer, replacing rate and window for them
+    // TODO: the vec is not safe for high concurrency
+    cache: Arc<DashMap<String, Vec<DateTime<Utc>>>>,
+    _cache: HashMap<String, String>,
 }
 
 impl RateLimiter {
@@ -19,23 +32,93 @@ impl RateLimiter {
         RateLimiter {
             rate,   // number of requests per window
             window, // window duration
-            cache: DashMap::new(),
-            _cache: HashMap::new()
+            plans: None,
+            cache: Arc::new(DashMap::new()),
+            _cache: HashMap::new(),
+        }
+    }
+
+    // the quota of the customer plan of the key, or the limit of the limiter
+    pub fn quota(&self, key: &str, plan: Option<&str>) -> Quota {
+        let own = Quota {
+            rate: self.rate,
+            window: self.window,
+        };
+        self.plans
+            .as_ref()
+            .and_then(|plans| plans.quota(key, plan))
+            .unwrap_or(own)
+    }
+
+    // keeps the counters of the limiter being replaced when the limit did not change,
+    // true when they were carried over, plan changes apply to the carried counters
+    pub fn carry_over(&mut self, previous: &RateLimiter) -> bool {
+        if self.rate != previous.rate || self.window != previous.window {
+            return false;
+        }
+        self.cache = previous.cache.clone();
+        true
+    }
+
+    pub fn snapshot(&self) -> LimiterState {
+        let visits = self
+            .cache
+            .iter()
+            .map(|entry| {
+                let visits = entry
+                    .value()
+                    .iter()
+                    .map(|visit| visit.timestamp_millis())
+                    .collect();
+                (entry.key().clone(), visits)
+            })
+            .collect();
+        LimiterState {
+            rate: self.rate,
+            window: self.window,
+            visits,
+        }
+    }
+
+    // only a limit with the same definition takes the saved counters, expired visits are dropped
+    pub fn restore(&self, state: &LimiterState) -> bool {
+        if self.rate != state.rate || self.window != state.window {
+            return false;
+        }
+        for (key, visits) in &state.visits {
+            let window = self.quota(key, None).window;
+            let oldest = Utc::now() - chrono::Duration::seconds(window as i64);
+            let visits: Vec<DateTime<Utc>> = visits
+                .iter()
+                .filter_map(|millis| DateTime::from_timestamp_millis(*millis))
+                .filter(|visit| *visit > oldest)
+                .collect();
+            if !visits.is_empty() {
+                self.cache.insert(key.clone(), visits);
+            }
         }
+        true
     }
 
     pub fn is_authorized(&self, user_id: &String) -> bool {
+        self.is_authorized_on_plan(user_id, None)
+    }
+
+    // the plan comes from the credentials of the request, e.g. an API key or a JWT claim
+    pub fn is_authorized_on_plan(&self, user_id: &String, plan: Option<&str>) -> bool {
+        let quota = self.quota(user_id, plan);
         match self.cache.get_mut(user_id) {
             Some(mut visits) => {
                 let now: DateTime<Utc> = Utc::now();
-                visits.retain(|e| e.clone() + chrono::Duration::seconds(self.window as i64) > now);
-                if visits.len() < self.rate.try_into().unwrap() {
+                visits.retain(|e| e.clone() + chrono::Duration::seconds(quota.window as i64) > now);
+                if visits.len() < quota.rate.try_into().unwrap() {
                     visits.push(Utc::now());
                     true
                 } else {
                     false
                 }
             }
+            None if quota.rate == 0 => false,
             None => {
                 self.cache.insert(user_id.clone(), vec![Utc::now()]);
                 true
@@ -68,7 +151,7 @@ async fn test_rate_limiter_concurrently() {
     let user_1: String = "1.0.0.0".into();
     let mut tasks: Vec<JoinHandle<bool>> = vec![];
     for _ in 0..3 {
-        tasks.push(spawn_task( rate_limiter.clone(), user_1.clone()).await);
+        tasks.push(spawn_task(rate_limiter.clone(), user_1.clone()).await);
     }
     let result: Vec<Result<bool, tokio::task::JoinError>> = futures::future::join_all(tasks).await;
     assert_eq!(result.iter().filter(|e| e.is_ok()).count(), 3);
@@ -80,6 +163,59 @@ async fn test_rate_limiter_concurrently() {
     assert_eq!(denied_count, 1);
 }
 
+#[test]
+fn test_rate_limiter_carry_over() {
+    let previous = RateLimiter::new(1, 60);
+    let user_1: String = "1.0.0.0".into();
+    assert!(previous.is_authorized(&user_1));
+
+    let mut unchanged = RateLimiter::new(1, 60);
+    assert!(unchanged.carry_over(&previous));
+    assert!(!unchanged.is_authorized(&user_1));
+
+    let mut raised = RateLimiter::new(2, 60);
+    assert!(!raised.carry_over(&previous));
+    assert!(raised.is_authorized(&user_1));
+}
+
+#[test]
+fn test_rate_limiter_snapshot_and_restore() {
+    let rate_limiter = RateLimiter::new(1, 60);
+    let user_1: String = "1.0.0.0".into();
+    assert!(rate_limiter.is_authorized(&user_1));
+    let state = rate_limiter.snapshot();
+
+    let restored = RateLimiter::new(1, 60);
+    assert!(restored.restore(&state));
+    assert!(!restored.is_authorized(&user_1));
+    assert!(!RateLimiter::new(1, 30).restore(&state));
+}
+
+#[test]
+fn test_rate_limiter_plans() {
+    let mut rate_limiter = RateLimiter::new(1, 60);
+    let quota = Quota {
+        rate: 2,
+        window: 60,
+    };
+    rate_limiter.plans = Some(Arc::new(Plans {
+        tiers: HashMap::from([("pro".to_string(), quota)]),
+        quotas: HashMap::from([("pro-key".to_string(), quota)]),
+        default: None,
+    }));
+    let pro: String = "pro-key".into();
+    let unknown: String = "other-key".into();
+
+    assert!(rate_limiter.is_authorized(&pro));
+    assert!(rate_limiter.is_authorized(&pro));
+    assert!(!rate_limiter.is_authorized(&pro));
+    assert!(rate_limiter.is_authorized(&unknown));
+    assert!(!rate_limiter.is_authorized(&unknown));
+    let tenant: String = "tenant-1".into();
+    assert!(rate_limiter.is_authorized_on_plan(&tenant, Some("pro")));
+    assert!(rate_limiter.is_authorized_on_plan(&tenant, Some("pro")));
+    assert!(!rate_limiter.is_authorized_on_plan(&tenant, Some("pro")));
+}
 
 async fn spawn_task(rate_limiter: Arc<RateLimiter>, user_1: String) -> JoinHandle<bool> {
     let rl = rate_limiter.clone();
@@ -1,10 +1,15 @@
 mod api;
+mod cli;
+mod config;
 mod engine;
 mod generated;
+mod server;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use axum::http::StatusCode;
+use axum::serve::ListenerExt;
 use axum::{
     Router,
     body::Body,
@@ -13,82 +18,236 @@ use axum::{
     response::IntoResponse,
     routing::get,
 };
+use clap::Parser;
 use reqwest::Client;
 use serde::de;
 use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
     api::{
-        http_proxy::HttpProxy,
-        model::{AuthorizationError, CallError, DownstreamError},
+        forwarding::ListenerProto,
+        grpc,
+        model::{AuthorizationError, CallError, DownstreamError, IdempotencyError, RoutingError},
         proxy::Proxy,
+        routing::RoutingTable,
+        timeouts::Timeouts,
+        websocket,
     },
-    engine::rate_limiter::RateLimiter,
+    cli::{Cli, Command, ConfigArgs},
+    config::reload::{Reloader, SharedProxy},
+    server::shutdown::{self, Shutdown},
+    server::tls::{self, SniResolver, TlsConfig, TlsListener},
 };
 
 #[tokio::main]
 async fn main() {
-    let port = "3000";
-    let original_uri = "https://www.google.com";
-
-    println!(
-        "Starting proxy server on port {}, forwading to {}",
-        port, original_uri
-    );
-
-    let engine: RateLimiter = RateLimiter::new(5, 60);
-    let client: Client = reqwest::Client::new();
-    let http_proxy: Arc<HttpProxy> = Arc::new(api::http_proxy::HttpProxy {
-        rate_limiter: engine,
+    let cli = Cli::parse();
+    let result = match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Serve(args)) => serve(args).await,
+        Some(Command::ValidateConfig(args)) => cli::validate_config(&args)
+            .map_err(|err| format!("Failed to load {}: {}", args.config.display(), err)),
+        Some(Command::CheckOpenapi { spec }) => cli::check_openapi(&spec),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(args: ConfigArgs) -> Result<(), String> {
+    let load_error = |err| format!("Failed to load {}: {}", args.config.display(), err);
+    let config = args.load().map_err(load_error)?;
+    let runtime = config.build().map_err(load_error)?;
+
+    println!("Starting proxy server on {}", runtime.http);
+
+    let client: Client = Timeouts::with_defaults()
+        .client_builder()
+        .build()
+        .expect("Failed to build http client");
+    let routing: Arc<RoutingTable> = Arc::new(runtime.routing);
+    routing.spawn_health_checks(&client);
+    let state_file = config.shutdown.state_file.as_deref();
+    if let Some(path) = state_file {
+        match shutdown::restore_limiters(path, &routing) {
+            Ok(restored) => println!("Restored {} limiters from {}", restored, path.display()),
+            Err(err) => println!("Limiter state not restored: {}", err),
+        }
+    }
+    let http_proxy = SharedProxy::new(api::http_proxy::HttpProxy {
+        routing,
         client,
-        original_url: original_uri.to_string(),
+        forwarding: runtime.forwarding,
     });
+    Reloader::new(args.clone(), http_proxy.clone(), &config).spawn(&config.reload);
+    let shutdown = Shutdown::new();
+    shutdown.spawn_signal_handler();
 
     let app_state = AppState {
-        original_url: original_uri.to_string(),
-        proxy: http_proxy,
+        proxy: http_proxy.clone(),
+        shutdown: shutdown.clone(),
     };
 
-    let axum_app = Router::new().fallback(handler).with_state(app_state);
+    let mut axum_app = Router::new();
+    if let Some(path) = &config.shutdown.readiness_path {
+        axum_app = axum_app.route(path, get(readiness));
+    }
+    let axum_app = axum_app.fallback(handler).with_state(app_state);
+
+    let mut servers = vec![];
+    if let Some((address, tls_config)) = runtime.https {
+        match SniResolver::load(tls_config.certificates.clone()) {
+            Ok(resolver) => servers
+                .push(serve_tls(address, tls_config, resolver, axum_app.clone(), &shutdown).await),
+            Err(err) => println!("HTTPS listener disabled: {}", err),
+        }
+    }
+
+    let listener = TcpListener::bind(runtime.http).await.unwrap();
+    println!("Listening on {}", runtime.http);
+    let draining = shutdown.wait();
+    servers.push(tokio::spawn(async move {
+        axum::serve(
+            listener,
+            axum_app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(draining)
+        .await
+        .unwrap()
+    }));
+
+    // the servers return once their connections are closed
+    let grace_period = config.shutdown.grace_period;
+    let draining = shutdown.wait();
+    tokio::select! {
+        _ = futures::future::join_all(servers) => println!("Connections drained"),
+        _ = async { draining.await; tokio::time::sleep(grace_period).await } => {
+            println!("Grace period over, closing the remaining connections")
+        }
+    }
+    if let Some(path) = state_file {
+        match shutdown::save_limiters(path, &http_proxy.current().routing) {
+            Ok(()) => println!("Limiter state saved to {}", path.display()),
+            Err(err) => println!("Limiter state not saved: {}", err),
+        }
+    }
+    Ok(())
+}
+
+async fn serve_tls(
+    address: SocketAddr,
+    tls_config: TlsConfig,
+    resolver: SniResolver,
+    app: Router,
+    shutdown: &Shutdown,
+) -> JoinHandle<()> {
+    let resolver = Arc::new(resolver);
+    tls::spawn_reload(resolver.clone(), tls_config.reload_interval);
+    let server_config = tls::server_config(resolver).expect("Failed to build TLS configuration");
+    let tcp_listener = TcpListener::bind(address).await.unwrap();
+    let listener = TlsListener::new(tcp_listener, TlsAcceptor::from(Arc::new(server_config)))
+        .unwrap()
+        // tapping the stream also makes the client address available as ConnectInfo
+        .tap_io(|tls_stream| {
+            let _ = tls_stream.get_ref().0.set_nodelay(true);
+        });
+    let app = app.layer(axum::Extension(ListenerProto("https")));
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+    println!("Listening for HTTPS on {}", address);
+    let draining = shutdown.wait();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(draining)
         .await
-        .unwrap();
-    print!("Listening on port {}", port);
-    let _ = axum::serve(listener, axum_app).await.unwrap();
+        .unwrap()
+    })
 }
 
 #[derive(Clone)]
 struct AppState {
-    original_url: String,
-    proxy: Arc<HttpProxy>,
+    proxy: SharedProxy,
+    shutdown: Shutdown,
+}
+
+// not ready as soon as the shutdown begins, so that no new traffic is sent here
+async fn readiness(State(app_state): State<AppState>) -> Response<Body> {
+    if app_state.shutdown.is_draining() {
+        construct_response(StatusCode::SERVICE_UNAVAILABLE, "Shutting Down")
+    } else {
+        construct_response(StatusCode::OK, "Ready")
+    }
 }
 
 #[axum::debug_handler]
 async fn handler(State(app_state): State<AppState>, req: Request<Body>) -> Response<Body> {
     println!("Received request: {:?}", req);
-    let res = app_state.proxy.proxy_handler(req).await;
-
-    if let Err(err_res) = res {
-        match err_res {
-            CallError::Authorization(AuthorizationError::TooManyQueries) => {
-                return construct_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
-            }
-            CallError::Authorization(AuthorizationError::IpHeaderMissing) => {
-                return construct_response(StatusCode::BAD_REQUEST, "IP Header Missing");
-            }
-            CallError::Downstream(DownstreamError::DownstreamError { response }) => {
-                return response;
-            }
-            _ => {
-                return construct_response(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal Proxy Server Error",
-                );
-            }
-        }
+    let proxy = app_state.proxy.current();
+    let req = match proxy.prepare_authentication(req).await {
+        Ok(req) => req,
+        Err(err_res) => return error_response(err_res),
     };
-    Response::new(Body::from("Proxied response"))
+    if websocket::is_upgrade_request(&req) {
+        return match proxy.websocket_handler(req).await {
+            Ok(response) => response,
+            Err(err_res) => error_response(err_res),
+        };
+    }
+    if grpc::is_grpc_request(&req) {
+        return match proxy.grpc_handler(req).await {
+            Ok(response) => response,
+            Err(err_res) => grpc::error_response(err_res),
+        };
+    }
+
+    let res = proxy.proxy_handler(req).await;
+
+    match res {
+        Ok(response) => response,
+        Err(err_res) => error_response(err_res),
+    }
+}
+
+fn error_response(err_res: CallError) -> Response<Body> {
+    match err_res {
+        CallError::Authorization(AuthorizationError::TooManyQueries) => {
+            construct_response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests")
+        }
+        CallError::Authorization(AuthorizationError::IpHeaderMissing) => {
+            construct_response(StatusCode::BAD_REQUEST, "IP Header Missing")
+        }
+        CallError::Authorization(AuthorizationError::Unauthenticated) => {
+            construct_response(StatusCode::UNAUTHORIZED, "Unauthorized")
+        }
+        CallError::Routing(RoutingError::NoRoute) => {
+            construct_response(StatusCode::NOT_FOUND, "No Route Found")
+        }
+        CallError::Idempotency(IdempotencyError::Conflict) => construct_response(
+            StatusCode::CONFLICT,
+            "Request With This Idempotency-Key In Progress",
+        ),
+        CallError::Idempotency(IdempotencyError::Sent) => construct_response(
+            StatusCode::CONFLICT,
+            "Request With This Idempotency-Key Already Sent",
+        ),
+        CallError::Downstream(DownstreamError::Unavailable)
+        | CallError::Downstream(DownstreamError::CircuitOpen) => {
+            construct_response(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable")
+        }
+        CallError::Downstream(DownstreamError::Timeout) => {
+            construct_response(StatusCode::GATEWAY_TIMEOUT, "Gateway Timeout")
+        }
+        CallError::Downstream(DownstreamError::DownstreamError { response }) => response,
+        _ => construct_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Proxy Server Error",
+        ),
+    }
 }
 
 fn construct_response(status: StatusCode, body: &str) -> Response<Body> {
@@ -0,0 +1,2 @@
+pub mod shutdown;
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,151 @@
+use crate::api::routing::RoutingTable;
+use crate::engine::rate_limiter::LimiterState;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Json(e) => write!(f, "Invalid limiter state: {}", e),
+        }
+    }
+}
+
+// Set once, on SIGTERM or Ctrl-C: readiness fails right away and listeners stop accepting
+#[derive(Clone)]
+pub struct Shutdown {
+    draining: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            draining: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn spawn_signal_handler(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(err) => {
+                    println!("Graceful shutdown on SIGTERM disabled: {}", err);
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = terminate.recv() => println!("SIGTERM received, draining connections"),
+                _ = tokio::signal::ctrl_c() => println!("Interrupted, draining connections"),
+            }
+            shutdown.begin();
+        });
+    }
+
+    pub fn begin(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    // resolves once the shutdown has begun
+    pub fn wait(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut draining = self.draining.subscribe();
+        async move {
+            let _ = draining.wait_for(|draining| *draining).await;
+        }
+    }
+}
+
+// counters of limits that no longer exist, or changed, are left out on restore
+pub fn save_limiters(path: &Path, routing: &RoutingTable) -> Result<(), StateError> {
+    let states: HashMap<String, LimiterState> = routing
+        .limiters()
+        .into_iter()
+        .map(|(name, limiter)| (name, limiter.snapshot()))
+        .collect();
+    let json = serde_json::to_vec(&states).map_err(StateError::Json)?;
+    std::fs::write(path, json).map_err(StateError::Io)
+}
+
+// returns the number of limiters restored, nothing is restored without a state file
+pub fn restore_limiters(path: &Path, routing: &RoutingTable) -> Result<usize, StateError> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(StateError::Io(err)),
+    };
+    let states: HashMap<String, LimiterState> =
+        serde_json::from_slice(&json).map_err(StateError::Json)?;
+    let restored = routing
+        .limiters()
+        .into_iter()
+        .filter(|(name, limiter)| states.get(name).is_some_and(|state| limiter.restore(state)))
+        .count();
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::routing::{Route, Upstream};
+    use crate::engine::rate_limiter::RateLimiter;
+
+    fn routing(rate: u64) -> RoutingTable {
+        RoutingTable::new(
+            vec![Upstream::new(
+                "pets",
+                "http://pets:8080",
+                RateLimiter::new(rate, 60),
+            )],
+            vec![Route::new(None, "/", "pets")],
+        )
+    }
+
+    #[test]
+    fn test_save_and_restore_limiters() {
+        let path = std::env::temp_dir().join(format!("limiters-{}.json", std::process::id()));
+        let user: String = "1.0.0.0".into();
+        let is_authorized = |routing: &RoutingTable| {
+            let route = routing.resolve(None, "/").unwrap();
+            route.upstream.rate_limiter.is_authorized(&user)
+        };
+        let before = routing(1);
+        assert!(is_authorized(&before));
+        save_limiters(&path, &before).unwrap();
+
+        let after = routing(1);
+        assert_eq!(restore_limiters(&path, &after).unwrap(), 1);
+        assert!(!is_authorized(&after));
+
+        // the limit changed in between
+        let changed = routing(2);
+        assert_eq!(restore_limiters(&path, &changed).unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_wakes_waiters() {
+        let shutdown = Shutdown::new();
+        let waiter = tokio::spawn(shutdown.wait());
+        assert!(!shutdown.is_draining());
+
+        shutdown.begin();
+        assert!(shutdown.is_draining());
+        waiter.await.unwrap();
+    }
+}
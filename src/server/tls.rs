@@ -0,0 +1,321 @@
+use axum::serve::Listener;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+// connections waiting for the server to pick them up once their handshake is done
+const ACCEPT_BACKLOG: usize = 128;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    #[serde(default)]
+    pub hostnames: Vec<String>, // served for these SNI names
+    pub cert_path: PathBuf, // PEM, leaf certificate first
+    pub key_path: PathBuf,  // PEM, PKCS#8, PKCS#1 or SEC1
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub certificates: Vec<CertificateConfig>, // the first one is served when SNI does not match
+    pub reload_interval: Duration,
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    InvalidKey(PathBuf, rustls::Error),
+    NoCertificate,
+    Config(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pem(path, e) => write!(f, "Failed to read {}: {:?}", path.display(), e),
+            Self::InvalidKey(path, e) => write!(f, "Invalid private key {}: {}", path.display(), e),
+            Self::NoCertificate => write!(f, "No certificate configured"),
+            Self::Config(e) => write!(f, "Invalid TLS configuration: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LoadedCertificates {
+    by_hostname: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    modified: Vec<Option<SystemTime>>, // of the PEM files, to detect changes
+}
+
+// Picks the certificate from the SNI name, certificates can be swapped at runtime
+#[derive(Debug)]
+pub struct SniResolver {
+    certificates: Vec<CertificateConfig>,
+    loaded: RwLock<LoadedCertificates>,
+}
+
+impl SniResolver {
+    pub fn load(certificates: Vec<CertificateConfig>) -> Result<SniResolver, TlsError> {
+        let resolver = SniResolver {
+            certificates,
+            loaded: RwLock::new(LoadedCertificates::default()),
+        };
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    // on error the certificates already loaded are kept
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let mut loaded = LoadedCertificates::default();
+        for certificate in &self.certificates {
+            let certified_key = Arc::new(load_certified_key(certificate)?);
+            for hostname in &certificate.hostnames {
+                loaded
+                    .by_hostname
+                    .insert(hostname.to_ascii_lowercase(), certified_key.clone());
+            }
+            loaded.default.get_or_insert(certified_key);
+        }
+        if loaded.default.is_none() {
+            return Err(TlsError::NoCertificate);
+        }
+        loaded.modified = self.files_modified();
+        *self.loaded.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        if self.files_modified() == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    fn files_modified(&self) -> Vec<Option<SystemTime>> {
+        self.certificates
+            .iter()
+            .flat_map(|c| [&c.cert_path, &c.key_path])
+            .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+        client_hello
+            .server_name()
+            .and_then(|name| loaded.by_hostname.get(&name.to_ascii_lowercase()))
+            .or(loaded.default.as_ref())
+            .cloned()
+    }
+}
+
+fn load_certified_key(certificate: &CertificateConfig) -> Result<CertifiedKey, TlsError> {
+    let cert_path = &certificate.cert_path;
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(cert_path.clone(), e))?;
+    let key = PrivateKeyDer::from_pem_file(&certificate.key_path)
+        .map_err(|e| TlsError::Pem(certificate.key_path.clone(), e))?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|e| TlsError::InvalidKey(certificate.key_path.clone(), e))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+pub fn server_config(resolver: Arc<SniResolver>) -> Result<ServerConfig, TlsError> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Config)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    // h2 first so multiplexed clients are not downgraded
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+// polls the PEM files, a failed reload keeps serving the previous certificates
+pub fn spawn_reload(resolver: Arc<SniResolver>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match resolver.reload_if_changed() {
+                Ok(true) => println!("TLS certificates reloaded"),
+                Ok(false) => {}
+                Err(err) => println!("TLS certificates reload failed: {}", err),
+            }
+        }
+    });
+}
+
+// Handshakes run in their own tasks so a slow client does not block the others
+pub struct TlsListener {
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            // stops once the listener is dropped
+            while !tx.is_closed() {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        println!("TLS accept error: {}", err);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(tls_stream) => {
+                            let _ = tx.send((tls_stream, addr)).await;
+                        }
+                        Err(err) => println!("TLS handshake failed with {}: {}", addr, err),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            accepted,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(connection) => connection,
+            // the accept task only stops with the runtime
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    const RESOURCES: &str = "src/resources/tls";
+
+    fn certificate(hostname: &str) -> CertificateConfig {
+        CertificateConfig {
+            hostnames: vec![hostname.to_string()],
+            cert_path: format!("{}/{}.cert.pem", RESOURCES, hostname).into(),
+            key_path: format!("{}/{}.key.pem", RESOURCES, hostname).into(),
+        }
+    }
+
+    // the certificate the server presented for the given SNI name
+    async fn handshake(addr: SocketAddr, sni: &str) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        for hostname in ["localhost", "api.example.com"] {
+            roots
+                .add(CertificateDer::from_pem_file(&certificate(hostname).cert_path).unwrap())
+                .unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let tls_stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(sni.to_string()).unwrap(), stream)
+            .await
+            .unwrap();
+        tls_stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_sni_certificate_selection() {
+        let resolver = Arc::new(
+            SniResolver::load(vec![
+                certificate("localhost"),
+                certificate("api.example.com"),
+            ])
+            .unwrap(),
+        );
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(resolver).unwrap()));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let mut listener = TlsListener::new(tcp_listener, acceptor).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let _connection = listener.accept().await;
+            }
+        });
+
+        let expected =
+            CertificateDer::from_pem_file(&certificate("api.example.com").cert_path).unwrap();
+        assert_eq!(handshake(addr, "api.example.com").await, expected);
+        let expected = CertificateDer::from_pem_file(&certificate("localhost").cert_path).unwrap();
+        assert_eq!(handshake(addr, "localhost").await, expected);
+    }
+
+    #[test]
+    fn test_reload_certificates() {
+        let dir = std::env::temp_dir().join(format!("rate-limiter-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = CertificateConfig {
+            hostnames: vec!["localhost".to_string()],
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        let install = |hostname: &str| {
+            fs::copy(&certificate(hostname).cert_path, &config.cert_path).unwrap();
+            fs::copy(&certificate(hostname).key_path, &config.key_path).unwrap();
+        };
+        let served = |resolver: &SniResolver| {
+            resolver.loaded.read().unwrap().by_hostname["localhost"].cert[0].clone()
+        };
+
+        install("localhost");
+        let resolver = SniResolver::load(vec![config.clone()]).unwrap();
+        let before = served(&resolver);
+
+        install("api.example.com");
+        resolver.reload().unwrap();
+        assert_ne!(served(&resolver), before);
+
+        // a broken file keeps the current certificate
+        fs::write(&config.key_path, "not a key").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_ne!(served(&resolver), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}